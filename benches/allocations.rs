@@ -0,0 +1,219 @@
+//! Measures the heap allocations and time of a typical paywall rule evaluation, both from its
+//! JSON definition and prepared once and evaluated with a different host each time, and of the
+//! two-step path of parsing the rule to an AST and evaluating the serialized AST against the
+//! typed `parse_and_evaluate`, and of transforming a large, deeply nested rule for null safety.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+use cel_eval::{
+    evaluate_ast_with_context, evaluate_on_json, evaluate_with_context, parse_to_ast,
+    EvaluationOptions, HostContext, PassableMap, PassableValue, PreparedEvaluator, ResultCallback,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Answers every host call synchronously, like most mobile hosts do
+struct Host {
+    results: HashMap<String, String>,
+}
+
+impl HostContext for Host {
+    fn computed_property(&self, name: String, _args: String, callback: Arc<dyn ResultCallback>) {
+        callback.on_result(self.results[&name].clone());
+    }
+
+    fn device_property(&self, name: String, _args: String, callback: Arc<dyn ResultCallback>) {
+        callback.on_result(self.results[&name].clone());
+    }
+}
+
+const DEFINITION: &str = r#"{
+    "variables": {"map": {
+        "user": {"type": "map", "value": {
+            "plan": {"type": "string", "value": "free"},
+            "credits": {"type": "int", "value": 3},
+            "seenPaywall": {"type": "string", "value": "false"}
+        }},
+        "device": {"type": "map", "value": {
+            "platform": {"type": "string", "value": "ios"},
+            "appVersion": {"type": "string", "value": "4.2.0"}
+        }}
+    }},
+    "device": {
+        "daysSince": [{"type": "string", "value": "event_name"}],
+        "hoursSince": [{"type": "string", "value": "event_name"}],
+        "minutesSince": [{"type": "string", "value": "event_name"}],
+        "monthsSince": [{"type": "string", "value": "event_name"}]
+    },
+    "computed": {
+        "daysSince": [{"type": "string", "value": "event_name"}],
+        "placementsFired": [{"type": "string", "value": "placement"}],
+        "subscriptionStatus": []
+    },
+    "expression": "user.plan == 'free' && user.credits < 5 && !user.seenPaywall && device.platform == 'ios' && device.daysSince('app_install') > 3 && computed.placementsFired('campaign') < 2 && computed.subscriptionStatus() != 'ACTIVE'"
+}"#;
+
+const ITERATIONS: u32 = 10_000;
+
+fn measure(label: &str, mut evaluate: impl FnMut(u32)) {
+    let (allocations, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    );
+    let start = Instant::now();
+    for iteration in 0..ITERATIONS {
+        evaluate(iteration);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {:.1} allocations, {:.0} bytes and {:.1?} per evaluation",
+        label,
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / ITERATIONS as f64,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) as f64 / ITERATIONS as f64,
+        elapsed / ITERATIONS
+    );
+}
+
+fn host(subscription_status: &str) -> Arc<Host> {
+    Arc::new(Host {
+        results: HashMap::from([
+            ("daysSince", PassableValue::Int(7)),
+            ("placementsFired", PassableValue::Int(1)),
+            (
+                "subscriptionStatus",
+                PassableValue::String(subscription_status.to_string()),
+            ),
+        ])
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), serde_json::to_string(&value).unwrap()))
+        .collect(),
+    })
+}
+
+fn main() {
+    // One host per tenant, alternated between evaluations
+    let hosts = [host("INACTIVE"), host("ACTIVE")];
+    let matched = "{\"Ok\":{\"type\":\"bool\",\"value\":true}}";
+
+    // Warm up, filling the expression cache
+    assert_eq!(
+        evaluate_with_context(DEFINITION.to_string(), hosts[0].clone()),
+        matched,
+        "the benchmarked rule should match"
+    );
+    measure("evaluate_with_context", |_| {
+        evaluate_with_context(DEFINITION.to_string(), hosts[0].clone());
+    });
+    measure("evaluate_with_context, swapping hosts", |iteration| {
+        evaluate_with_context(
+            DEFINITION.to_string(),
+            hosts[iteration as usize % 2].clone(),
+        );
+    });
+
+    // The same rule parsed to an AST first, the AST being embedded in the definition
+    let mut ast_definition: serde_json::Value = serde_json::from_str(DEFINITION).unwrap();
+    let expression = ast_definition["expression"].as_str().unwrap().to_string();
+    let with_ast = |definition: &mut serde_json::Value, ast: String| {
+        definition["expression"] = serde_json::from_str(&ast).unwrap();
+        definition.to_string()
+    };
+    let stored_ast = with_ast(&mut ast_definition, parse_to_ast(expression.clone()));
+    assert_eq!(
+        evaluate_ast_with_context(stored_ast.clone(), hosts[0].clone()),
+        matched
+    );
+    measure("parse_to_ast, then evaluate_ast_with_context", |_| {
+        let definition = with_ast(&mut ast_definition, parse_to_ast(expression.clone()));
+        evaluate_ast_with_context(definition, hosts[0].clone());
+    });
+    measure("evaluate_ast_with_context, with a stored AST", |_| {
+        evaluate_ast_with_context(stored_ast.clone(), hosts[0].clone());
+    });
+
+    // Parsed and evaluated in one typed call, without serializing the AST
+    let context: serde_json::Value = serde_json::from_str(DEFINITION).unwrap();
+    let variables: PassableMap = serde_json::from_value(context["variables"].clone()).unwrap();
+    let functions = |kind: &str| -> Option<HashMap<String, Vec<PassableValue>>> {
+        serde_json::from_value(context[kind].clone()).unwrap()
+    };
+    let (computed, device) = (functions("computed"), functions("device"));
+    let parse_and_evaluate = |host: Arc<Host>| {
+        cel_eval::parse_and_evaluate(
+            expression.clone(),
+            variables.clone(),
+            computed.clone(),
+            device.clone(),
+            EvaluationOptions::default(),
+            host,
+        )
+    };
+    assert_eq!(
+        parse_and_evaluate(hosts[0].clone()),
+        Ok(PassableValue::Bool(true))
+    );
+    measure("parse_and_evaluate", |_| {
+        parse_and_evaluate(hosts[0].clone()).unwrap();
+    });
+
+    let prepared = PreparedEvaluator::new(DEFINITION.to_string()).unwrap();
+    let no_variables = r#"{"map": {}}"#;
+    assert_eq!(
+        prepared.evaluate_with(no_variables.to_string(), hosts[0].clone()),
+        matched
+    );
+    measure(
+        "PreparedEvaluator::evaluate_with, swapping hosts",
+        |iteration| {
+            prepared.evaluate_with(
+                no_variables.to_string(),
+                hosts[iteration as usize % 2].clone(),
+            );
+        },
+    );
+
+    // `evaluate_on_json` transforms its expression on every call, uncached. The relations are
+    // nested on their right sides, which the transformation once walked twice per level
+    let mut nested = "user.credits > 0".to_string();
+    for level in 0..60 {
+        nested = format!("user.flag{} == ({})", level % 4, nested);
+    }
+    let large = (0..20)
+        .map(|rule| format!("(user.tier == 'rule{}' || {})", rule, nested))
+        .collect::<Vec<_>>()
+        .join(" && ");
+    let data = r#"{"user": {"credits": 3}}"#;
+    assert!(evaluate_on_json(large.clone(), data.to_string()).contains("\"Ok\""));
+    measure("evaluate_on_json, with a large nested rule", |_| {
+        evaluate_on_json(large.clone(), data.to_string());
+    });
+}
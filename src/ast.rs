@@ -1,4 +1,5 @@
-use crate::models::{PassableMap, PassableValue};
+use crate::models::{EvaluationOptions, PassableMap, PassableValue};
+use crate::COMPREHENSION_MACROS;
 use cel_parser::Member::{Attribute, Fields, Index};
 use cel_parser::{ArithmeticOp, Atom, Expression, Member, RelationOp, UnaryOp};
 use serde::{Deserialize, Serialize};
@@ -11,6 +12,10 @@ pub(crate) struct ASTExecutionContext {
     pub(crate) expression: JSONExpression,
     pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
     pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    #[serde(default)]
+    pub(crate) options: EvaluationOptions,
+    #[serde(default, alias = "priorResults")]
+    pub(crate) prior_results: HashMap<String, PassableValue>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -323,6 +328,397 @@ impl From<Atom> for JSONAtom {
     }
 }
 
+/**
+ * Renders an expression back into CEL source, adding parentheses only where operator
+ * precedence requires them. Parsing the output yields an equivalent expression, except for
+ * unsigned literals which are rendered as `uint(n)` as the parser rejects the `u` suffix.
+ */
+pub(crate) fn expression_to_source(expr: &Expression) -> String {
+    fn list(items: &[Expression]) -> String {
+        items
+            .iter()
+            .map(expression_to_source)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    match expr {
+        Expression::Ternary(condition, if_true, if_false) => format!(
+            "{} ? {} : {}",
+            operand(condition, 1),
+            operand(if_true, 1),
+            operand(if_false, 0)
+        ),
+        Expression::Or(left, right) => format!("{} || {}", operand(left, 1), operand(right, 2)),
+        Expression::And(left, right) => format!("{} && {}", operand(left, 2), operand(right, 3)),
+        Expression::Relation(left, op, right) => format!(
+            "{} {} {}",
+            relation_operand_to_source(left),
+            relation_operator(op),
+            relation_operand_to_source(right)
+        ),
+        Expression::Arithmetic(left, op, right) => {
+            let level = precedence(expr);
+            let op = match op {
+                ArithmeticOp::Add => "+",
+                ArithmeticOp::Subtract => "-",
+                ArithmeticOp::Multiply => "*",
+                ArithmeticOp::Divide => "/",
+                ArithmeticOp::Modulus => "%",
+            };
+            format!(
+                "{} {} {}",
+                operand(left, level),
+                op,
+                operand(right, level + 1)
+            )
+        }
+        Expression::Unary(op, operand_expr) => {
+            let op = match op {
+                UnaryOp::Not => "!",
+                UnaryOp::DoubleNot => "!!",
+                UnaryOp::Minus => "-",
+                UnaryOp::DoubleMinus => "--",
+            };
+            format!("{}{}", op, operand(operand_expr, 7))
+        }
+        Expression::Member(target, member) => match member.as_ref() {
+            Attribute(name) => format!("{}.{}", operand(target, 7), name),
+            Index(index) => format!("{}[{}]", operand(target, 7), expression_to_source(index)),
+            Fields(fields) => format!(
+                "{}{{{}}}",
+                operand(target, 7),
+                fields
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, expression_to_source(value)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        },
+        Expression::FunctionCall(function, target, args) => match target {
+            Some(target) => format!(
+                "{}.{}({})",
+                operand(target, 7),
+                operand(function, 7),
+                list(args)
+            ),
+            None => format!("{}({})", operand(function, 7), list(args)),
+        },
+        Expression::List(items) => format!("[{}]", list(items)),
+        Expression::Map(entries) => format!(
+            "{{{}}}",
+            entries
+                .iter()
+                .map(|(key, value)| format!(
+                    "{}: {}",
+                    expression_to_source(key),
+                    expression_to_source(value)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Expression::Atom(atom) => match atom {
+            Atom::Int(i) => i.to_string(),
+            Atom::UInt(u) => format!("uint({})", u),
+            Atom::Float(f) => format!("{:?}", f),
+            Atom::String(s) => serde_json::to_string(s.as_str()).unwrap(),
+            Atom::Bytes(bytes) => format!(
+                "b\"{}\"",
+                bytes
+                    .iter()
+                    .map(|b| format!("\\x{:02x}", b))
+                    .collect::<String>()
+            ),
+            Atom::Bool(b) => b.to_string(),
+            Atom::Null => "null".to_string(),
+        },
+        Expression::Ident(name) => name.to_string(),
+    }
+}
+
+// Binding strength of each expression kind, from loosest (ternary) to tightest (member/primary)
+fn precedence(expr: &Expression) -> u8 {
+    match expr {
+        Expression::Ternary(..) => 0,
+        Expression::Or(..) => 1,
+        Expression::And(..) => 2,
+        Expression::Relation(..) => 3,
+        Expression::Arithmetic(_, ArithmeticOp::Add | ArithmeticOp::Subtract, _) => 4,
+        Expression::Arithmetic(..) => 5,
+        Expression::Unary(..) => 6,
+        _ => 7,
+    }
+}
+
+// The source of an operand, parenthesized if it binds looser than `min_precedence`
+fn operand(expr: &Expression, min_precedence: u8) -> String {
+    let source = expression_to_source(expr);
+    if precedence(expr) < min_precedence {
+        format!("({})", source)
+    } else {
+        source
+    }
+}
+
+/**
+ * Renders an operand of a relation into CEL source, parenthesized as in the source of the
+ * relation.
+ */
+pub(crate) fn relation_operand_to_source(expr: &Expression) -> String {
+    operand(expr, 4)
+}
+
+/**
+ * The source of a relation's operator, e.g. `<=` or `in`.
+ */
+pub(crate) fn relation_operator(op: &RelationOp) -> &'static str {
+    match op {
+        RelationOp::LessThan => "<",
+        RelationOp::LessThanEq => "<=",
+        RelationOp::GreaterThan => ">",
+        RelationOp::GreaterThanEq => ">=",
+        RelationOp::Equals => "==",
+        RelationOp::NotEquals => "!=",
+        RelationOp::In => "in",
+    }
+}
+
+/**
+ * Calls `visit` on the expression and every expression nested in it, parents before children.
+ */
+pub(crate) fn visit_expressions(expr: &Expression, visit: &mut impl FnMut(&Expression)) {
+    visit(expr);
+    match expr {
+        Expression::Arithmetic(left, _, right)
+        | Expression::Relation(left, _, right)
+        | Expression::And(left, right)
+        | Expression::Or(left, right) => {
+            visit_expressions(left, visit);
+            visit_expressions(right, visit);
+        }
+        Expression::Ternary(condition, if_true, if_false) => {
+            visit_expressions(condition, visit);
+            visit_expressions(if_true, visit);
+            visit_expressions(if_false, visit);
+        }
+        Expression::Unary(_, operand) => visit_expressions(operand, visit),
+        Expression::Member(operand, member) => {
+            visit_expressions(operand, visit);
+            match member.as_ref() {
+                Index(index) => visit_expressions(index, visit),
+                Fields(fields) => fields
+                    .iter()
+                    .for_each(|(_, value)| visit_expressions(value, visit)),
+                Attribute(_) => {}
+            }
+        }
+        Expression::FunctionCall(func, this, args) => {
+            visit_expressions(func, visit);
+            if let Some(this) = this {
+                visit_expressions(this, visit);
+            }
+            args.iter().for_each(|arg| visit_expressions(arg, visit));
+        }
+        Expression::List(items) => items.iter().for_each(|item| visit_expressions(item, visit)),
+        Expression::Map(entries) => entries.iter().for_each(|(key, value)| {
+            visit_expressions(key, visit);
+            visit_expressions(value, visit);
+        }),
+        Expression::Atom(_) | Expression::Ident(_) => {}
+    }
+}
+
+/**
+ * Whether the expression is nested deeper than `depth` levels, the expression itself being the
+ * first, recursing no deeper than that to find out.
+ */
+pub(crate) fn deeper_than(expr: &Expression, depth: usize) -> bool {
+    if depth == 0 {
+        return true;
+    }
+    let deeper = |expr: &Expression| deeper_than(expr, depth - 1);
+    match expr {
+        Expression::Arithmetic(left, _, right)
+        | Expression::Relation(left, _, right)
+        | Expression::And(left, right)
+        | Expression::Or(left, right) => deeper(left) || deeper(right),
+        Expression::Ternary(condition, if_true, if_false) => {
+            deeper(condition) || deeper(if_true) || deeper(if_false)
+        }
+        Expression::Unary(_, operand) => deeper(operand),
+        Expression::Member(operand, member) => {
+            deeper(operand)
+                || match member.as_ref() {
+                    Index(index) => deeper(index),
+                    Fields(fields) => fields.iter().any(|(_, value)| deeper(value)),
+                    Attribute(_) => false,
+                }
+        }
+        Expression::FunctionCall(func, this, args) => {
+            deeper(func) || this.as_deref().is_some_and(deeper) || args.iter().any(deeper)
+        }
+        Expression::List(items) => items.iter().any(deeper),
+        Expression::Map(entries) => entries
+            .iter()
+            .any(|(key, value)| deeper(key) || deeper(value)),
+        Expression::Atom(_) | Expression::Ident(_) => false,
+    }
+}
+
+/**
+ * Rebuilds the expression bottom-up, replacing every expression with the result of `map`
+ * once its children have been mapped.
+ */
+pub(crate) fn map_expressions(
+    expr: Expression,
+    map: &mut impl FnMut(Expression) -> Expression,
+) -> Expression {
+    let mut recurse = |expr: Box<Expression>| Box::new(map_expressions(*expr, map));
+    let mapped = match expr {
+        Expression::Arithmetic(left, op, right) => {
+            Expression::Arithmetic(recurse(left), op, recurse(right))
+        }
+        Expression::Relation(left, op, right) => {
+            Expression::Relation(recurse(left), op, recurse(right))
+        }
+        Expression::And(left, right) => Expression::And(recurse(left), recurse(right)),
+        Expression::Or(left, right) => Expression::Or(recurse(left), recurse(right)),
+        Expression::Ternary(condition, if_true, if_false) => {
+            Expression::Ternary(recurse(condition), recurse(if_true), recurse(if_false))
+        }
+        Expression::Unary(op, operand) => Expression::Unary(op, recurse(operand)),
+        Expression::Member(operand, member) => {
+            let operand = recurse(operand);
+            let member = match *member {
+                Index(index) => Index(recurse(index)),
+                Fields(fields) => Fields(
+                    fields
+                        .into_iter()
+                        .map(|(name, value)| (name, map_expressions(value, map)))
+                        .collect(),
+                ),
+                attribute => attribute,
+            };
+            Expression::Member(operand, Box::new(member))
+        }
+        Expression::FunctionCall(func, this, args) => {
+            let func = recurse(func);
+            let this = this.map(&mut recurse);
+            let args = args
+                .into_iter()
+                .map(|arg| map_expressions(arg, map))
+                .collect();
+            Expression::FunctionCall(func, this, args)
+        }
+        Expression::List(items) => Expression::List(
+            items
+                .into_iter()
+                .map(|item| map_expressions(item, map))
+                .collect(),
+        ),
+        Expression::Map(entries) => Expression::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (map_expressions(key, map), map_expressions(value, map)))
+                .collect(),
+        ),
+        atom_or_ident => atom_or_ident,
+    };
+    map(mapped)
+}
+
+/**
+ * Like `map_expressions`, but also passes `map` the variables bound by the comprehension macros
+ * enclosing the expression, like `x` in the body of `items.all(x, x > 0)`, innermost last.
+ */
+pub(crate) fn map_scoped_expressions(
+    expr: Expression,
+    bound: &mut Vec<String>,
+    map: &mut impl FnMut(Expression, &[String]) -> Expression,
+) -> Expression {
+    let mut recurse = |expr: Box<Expression>, bound: &mut Vec<String>| {
+        Box::new(map_scoped_expressions(*expr, bound, map))
+    };
+    let mapped = match expr {
+        Expression::Arithmetic(left, op, right) => {
+            Expression::Arithmetic(recurse(left, bound), op, recurse(right, bound))
+        }
+        Expression::Relation(left, op, right) => {
+            Expression::Relation(recurse(left, bound), op, recurse(right, bound))
+        }
+        Expression::And(left, right) => {
+            Expression::And(recurse(left, bound), recurse(right, bound))
+        }
+        Expression::Or(left, right) => Expression::Or(recurse(left, bound), recurse(right, bound)),
+        Expression::Ternary(condition, if_true, if_false) => Expression::Ternary(
+            recurse(condition, bound),
+            recurse(if_true, bound),
+            recurse(if_false, bound),
+        ),
+        Expression::Unary(op, operand) => Expression::Unary(op, recurse(operand, bound)),
+        Expression::Member(operand, member) => {
+            let operand = recurse(operand, bound);
+            let member = match *member {
+                Index(index) => Index(recurse(index, bound)),
+                Fields(fields) => Fields(
+                    fields
+                        .into_iter()
+                        .map(|(name, value)| (name, map_scoped_expressions(value, bound, map)))
+                        .collect(),
+                ),
+                attribute => attribute,
+            };
+            Expression::Member(operand, Box::new(member))
+        }
+        Expression::FunctionCall(func, this, args) => {
+            let binding = match (func.as_ref(), args.as_slice()) {
+                (Expression::Ident(name), [Expression::Ident(binding), _])
+                    if COMPREHENSION_MACROS.contains(&name.as_str()) =>
+                {
+                    Some(binding.to_string())
+                }
+                _ => None,
+            };
+            let func = recurse(func, bound);
+            let this = this.map(|this| recurse(this, bound));
+            let args = args
+                .into_iter()
+                .enumerate()
+                .map(|(index, arg)| match &binding {
+                    // The variable a macro binds, and its body, the argument after it
+                    Some(binding) if index <= 1 => {
+                        bound.push(binding.clone());
+                        let body = map_scoped_expressions(arg, bound, map);
+                        bound.pop();
+                        body
+                    }
+                    _ => map_scoped_expressions(arg, bound, map),
+                })
+                .collect();
+            Expression::FunctionCall(func, this, args)
+        }
+        Expression::List(items) => Expression::List(
+            items
+                .into_iter()
+                .map(|item| map_scoped_expressions(item, bound, map))
+                .collect(),
+        ),
+        Expression::Map(entries) => Expression::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    (
+                        map_scoped_expressions(key, bound, map),
+                        map_scoped_expressions(value, bound, map),
+                    )
+                })
+                .collect(),
+        ),
+        atom_or_ident => atom_or_ident,
+    };
+    map(mapped, bound)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -547,4 +943,28 @@ mod tests {
         let back_to_map: JSONExpression = cel_map.into();
         assert_eq!(map_expr, back_to_map);
     }
+
+    #[test]
+    fn test_expression_to_source_round_trip() {
+        let sources = [
+            "a > 1 && (b == 2 || c < 3)",
+            "(a + b) * c - d / (e % f)",
+            "a - (b - c)",
+            "!(a && b) || -x.y[0] >= 2.5",
+            "a ? b : c ? d : e",
+            "(a ? b : c) ? d : e",
+            "device.daysSince(\"app_install\") in [1, 2, 3]",
+            "size({\"k\": 'v', 'n': null}) == 1 && b\"ab\" != b'cd'",
+            "has(user.name) ? user.name.startsWith(\"a\\\"b\") : false",
+        ];
+        for source in sources {
+            let expr = ExpressionParser::new().parse(source).unwrap();
+            let printed = expression_to_source(&expr);
+            let reparsed = ExpressionParser::new().parse(&printed).unwrap();
+            assert_eq!(expr, reparsed, "{} printed as {}", source, printed);
+        }
+
+        let expr = ExpressionParser::new().parse("a>1&&(b==2||c<3)").unwrap();
+        assert_eq!(expression_to_source(&expr), "a > 1 && (b == 2 || c < 3)");
+    }
 }
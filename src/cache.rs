@@ -0,0 +1,234 @@
+use crate::models::{Collation, IntegerDivision, PassableValue};
+use cel_parser::Expression;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Maximum number of expressions kept by default before the least recently used is evicted
+const MAX_ENTRIES: usize = 1024;
+
+/// Maximum number of evaluation results kept by default before the oldest is evicted
+const MAX_RESULTS: usize = 256;
+
+/**
+ * Cache of parsed and null-safety transformed expressions, safe to share across threads.
+ * Entries are keyed by the expression source together with the declared device and computed
+ * function names, as the transformation depends on them, and by the constant folding applied.
+ * When full, the least recently used expression is evicted to make room for a new one.
+ */
+pub(crate) struct ExpressionCache {
+    entries: RwLock<HashMap<String, CachedExpression>>,
+    max_entries: AtomicUsize,
+    // Ticks on every lookup, stamping entries with the time they were last used
+    clock: AtomicU64,
+}
+
+struct CachedExpression {
+    expression: Arc<Expression>,
+    last_used: AtomicU64,
+}
+
+impl ExpressionCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            max_entries: AtomicUsize::new(MAX_ENTRIES),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// The process wide cache used by the evaluation entry points
+    pub(crate) fn global() -> &'static ExpressionCache {
+        static CACHE: OnceLock<ExpressionCache> = OnceLock::new();
+        CACHE.get_or_init(ExpressionCache::new)
+    }
+
+    pub(crate) fn key(
+        expression: &str,
+        device: &HashMap<String, Vec<PassableValue>>,
+        computed: &HashMap<String, Vec<PassableValue>>,
+        folding: Option<(&IntegerDivision, Collation, bool)>,
+    ) -> String {
+        let mut device_names: Vec<&String> = device.keys().collect();
+        device_names.sort();
+        let mut computed_names: Vec<&String> = computed.keys().collect();
+        computed_names.sort();
+        serde_json::to_string(&(expression, device_names, computed_names, folding)).unwrap()
+    }
+
+    /**
+     * Returns the cached expression for the key, building and caching it on a miss.
+     * The expression is built outside the lock so a slow parse never blocks other threads;
+     * if two threads race on the same key the first inserted expression wins.
+     */
+    pub(crate) fn get_or_try_insert<E>(
+        &self,
+        key: String,
+        build: impl FnOnce() -> Result<Expression, E>,
+    ) -> Result<Arc<Expression>, E> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(cached) = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&key)
+        {
+            cached.last_used.store(now, Ordering::Relaxed);
+            return Ok(cached.expression.clone());
+        }
+
+        let expression = Arc::new(build()?);
+        let max_entries = self.max_entries.load(Ordering::Relaxed);
+        if max_entries == 0 {
+            return Ok(expression);
+        }
+        let mut entries = self
+            .entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !entries.contains_key(&key) {
+            evict(&mut entries, max_entries - 1);
+        }
+        let cached = entries.entry(key).or_insert(CachedExpression {
+            expression,
+            last_used: AtomicU64::new(now),
+        });
+        Ok(cached.expression.clone())
+    }
+
+    /// Sets how many expressions are kept, evicting the least recently used beyond it
+    pub(crate) fn configure(&self, max_entries: usize) {
+        self.max_entries.store(max_entries, Ordering::Relaxed);
+        let mut entries = self
+            .entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        evict(&mut entries, max_entries);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+}
+
+// Evicts the least recently used expressions until at most `max_entries` are left
+fn evict(entries: &mut HashMap<String, CachedExpression>, max_entries: usize) {
+    while entries.len() > max_entries {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+        entries.remove(&oldest);
+    }
+}
+
+/**
+ * Cache of evaluation results keyed by canonical context, for hosts re-evaluating the same
+ * rules against an unchanged context. Each lookup passes the time-to-live of its context, so
+ * an entry is only returned while it is younger than that. When full, the oldest result is
+ * evicted to make room for a new one.
+ */
+pub(crate) struct ResultCache {
+    entries: Mutex<HashMap<String, (Instant, PassableValue)>>,
+    max_results: AtomicUsize,
+}
+
+impl ResultCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_results: AtomicUsize::new(MAX_RESULTS),
+        }
+    }
+
+    /// The process wide cache used by the evaluation entry points
+    pub(crate) fn global() -> &'static ResultCache {
+        static CACHE: OnceLock<ResultCache> = OnceLock::new();
+        CACHE.get_or_init(ResultCache::new)
+    }
+
+    /// Returns the cached result for the context if it was stored less than `ttl` ago
+    pub(crate) fn get(&self, context: &str, ttl: Duration) -> Option<PassableValue> {
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match entries.get(context) {
+            Some((stored, value)) if stored.elapsed() < ttl => Some(value.clone()),
+            Some(_) => {
+                entries.remove(context);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, context: String, value: PassableValue) {
+        let max_results = self.max_results.load(Ordering::Relaxed);
+        if max_results == 0 {
+            return;
+        }
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !entries.contains_key(&context) {
+            evict_results(&mut entries, max_results - 1);
+        }
+        entries.insert(context, (Instant::now(), value));
+    }
+
+    /// Sets how many results are kept, evicting the oldest beyond it
+    pub(crate) fn configure(&self, max_results: usize) {
+        self.max_results.store(max_results, Ordering::Relaxed);
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        evict_results(&mut entries, max_results);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+}
+
+// Evicts the oldest results until at most `max_results` are left
+fn evict_results(entries: &mut HashMap<String, (Instant, PassableValue)>, max_results: usize) {
+    while entries.len() > max_results {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, (stored, _))| *stored)
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+        entries.remove(&oldest);
+    }
+}
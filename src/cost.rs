@@ -0,0 +1,184 @@
+use crate::models::{CostEstimate, PassableMap, PassableValue};
+use crate::COMPREHENSION_MACROS;
+use cel_parser::{Expression, Member, RelationOp};
+
+/// Cost of a call to a `device`/`computed` function, crossing into the host
+const HOST_CALL_COST: u64 = 100;
+/// Cost of a call to a built-in function or method, like `size` or `contains`
+const FUNCTION_CALL_COST: u64 = 5;
+/// Cost of an operator, a field read or an index
+const OPERATION_COST: u64 = 1;
+/// Number of elements assumed for collections whose size the context doesn't tell
+const DEFAULT_COLLECTION_SIZE: u64 = 10;
+
+/**
+ * What the cost of an expression is estimated from: the variables sizing the collections it
+ * iterates over and the host functions read as namespaces, like `device.network`.
+ */
+pub(crate) struct CostModel<'a> {
+    pub(crate) variables: &'a PassableMap,
+    pub(crate) namespaces: &'a [String],
+}
+
+impl CostModel<'_> {
+    /**
+     * Estimates the worst-case cost of evaluating the expression: both operands of `&&`/`||`
+     * and the costlier branch of a ternary are counted, and the body of a comprehension like
+     * `all(x, ...)` is counted once per element of the collection it iterates over.
+     */
+    pub(crate) fn estimate(&self, expr: &Expression) -> CostEstimate {
+        let mut estimate = CostEstimate::default();
+        match expr {
+            Expression::Arithmetic(left, _, right) => {
+                estimate.arithmetic_ops += 1;
+                estimate.cost += OPERATION_COST;
+                estimate.add(self.estimate(left));
+                estimate.add(self.estimate(right));
+            }
+            Expression::Relation(left, op, right) => {
+                estimate.comparisons += 1;
+                estimate.cost += OPERATION_COST;
+                if *op == RelationOp::In {
+                    // Membership compares against every element
+                    estimate.add(self.iterations(right, CostEstimate::default()));
+                }
+                estimate.add(self.estimate(left));
+                estimate.add(self.estimate(right));
+            }
+            Expression::And(left, right) | Expression::Or(left, right) => {
+                estimate.cost += OPERATION_COST;
+                estimate.add(self.estimate(left));
+                estimate.add(self.estimate(right));
+            }
+            Expression::Ternary(condition, if_true, if_false) => {
+                estimate.cost += OPERATION_COST;
+                estimate.add(self.estimate(condition));
+                let (if_true, if_false) = (self.estimate(if_true), self.estimate(if_false));
+                estimate.add(if if_true.cost >= if_false.cost {
+                    if_true
+                } else {
+                    if_false
+                });
+            }
+            Expression::Unary(_, operand) => {
+                estimate.cost += OPERATION_COST;
+                estimate.add(self.estimate(operand));
+            }
+            Expression::Member(operand, member) => {
+                if self.is_namespace(operand, member) {
+                    estimate.host_calls += 1;
+                    estimate.cost += HOST_CALL_COST;
+                    return estimate;
+                }
+                estimate.cost += OPERATION_COST;
+                estimate.add(self.estimate(operand));
+                match member.as_ref() {
+                    Member::Index(index) => estimate.add(self.estimate(index)),
+                    Member::Fields(fields) => fields
+                        .iter()
+                        .for_each(|(_, value)| estimate.add(self.estimate(value))),
+                    Member::Attribute(_) => {}
+                }
+            }
+            Expression::FunctionCall(func, this, args) => {
+                if is_host_call(func, this.as_deref()) {
+                    estimate.host_calls += 1;
+                    estimate.cost += HOST_CALL_COST;
+                } else {
+                    estimate.function_calls += 1;
+                    estimate.cost += FUNCTION_CALL_COST;
+                }
+                if let Some(this) = this {
+                    estimate.add(self.estimate(this));
+                }
+                match (func.as_ref(), this, args.as_slice()) {
+                    (Expression::Ident(name), Some(target), [Expression::Ident(_), body @ ..])
+                        if COMPREHENSION_MACROS.contains(&name.as_str()) =>
+                    {
+                        let mut per_element = CostEstimate::default();
+                        body.iter()
+                            .for_each(|body| per_element.add(self.estimate(body)));
+                        estimate.add(self.iterations(target, per_element));
+                    }
+                    _ => args.iter().for_each(|arg| estimate.add(self.estimate(arg))),
+                }
+            }
+            Expression::List(items) => {
+                estimate.largest_collection = items.len() as u64;
+                items
+                    .iter()
+                    .for_each(|item| estimate.add(self.estimate(item)));
+            }
+            Expression::Map(entries) => {
+                estimate.largest_collection = entries.len() as u64;
+                entries.iter().for_each(|(key, value)| {
+                    estimate.add(self.estimate(key));
+                    estimate.add(self.estimate(value));
+                });
+            }
+            Expression::Atom(_) | Expression::Ident(_) => {}
+        }
+        estimate
+    }
+
+    // The cost of visiting each element of a collection, `per_element` being the cost of one
+    fn iterations(&self, collection: &Expression, per_element: CostEstimate) -> CostEstimate {
+        let size = self.collection_size(collection);
+        let mut estimate = per_element.scaled(size);
+        estimate.iterations = estimate.iterations.saturating_add(size);
+        estimate.cost = estimate
+            .cost
+            .saturating_add(size.saturating_mul(OPERATION_COST));
+        estimate.largest_collection = estimate.largest_collection.max(size);
+        estimate
+    }
+
+    // The number of elements of a literal collection or a variable holding one
+    fn collection_size(&self, collection: &Expression) -> u64 {
+        let size = match collection {
+            Expression::List(items) => Some(items.len()),
+            Expression::Map(entries) => Some(entries.len()),
+            collection => self.variable(collection).and_then(|value| match value {
+                PassableValue::List(items) => Some(items.len()),
+                PassableValue::PMap(entries) => Some(entries.len()),
+                _ => None,
+            }),
+        };
+        size.map_or(DEFAULT_COLLECTION_SIZE, |size| size as u64)
+    }
+
+    // The value of a variable read like `user.purchases`
+    fn variable(&self, expr: &Expression) -> Option<&PassableValue> {
+        match expr {
+            Expression::Ident(name) => self.variables.map.get(name.as_str()),
+            Expression::Member(operand, member) => match (self.variable(operand)?, member.as_ref())
+            {
+                (PassableValue::PMap(fields), Member::Attribute(name)) => fields.get(name.as_str()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn is_namespace(&self, operand: &Expression, member: &Member) -> bool {
+        match (operand, member) {
+            (Expression::Ident(host), Member::Attribute(name)) => {
+                self.namespaces.contains(&format!("{}.{}", host, name))
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether the call is a `device.name(...)` or `computed.name(...)` host call
+fn is_host_call(func: &Expression, this: Option<&Expression>) -> bool {
+    let host = match (func, this) {
+        (Expression::Ident(_), Some(Expression::Ident(host))) => host,
+        (Expression::Member(operand, _), None) => match operand.as_ref() {
+            Expression::Ident(host) => host,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    host.as_str() == "device" || host.as_str() == "computed"
+}
@@ -0,0 +1,418 @@
+use crate::ast::{expression_to_source, visit_expressions};
+use crate::models::{EvaluationOptions, PassableValue};
+use crate::{builtin_arity_error, STANDARD_FUNCTIONS, SUPPORTED_FUNCTIONS};
+use cel_parser::{ArithmeticOp, Atom, Expression, UnaryOp};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+
+/**
+ * Structured description of why an expression failed to parse, with human friendly
+ * suggestions for common mistakes.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ParseFailure {
+    /// The parser's error message
+    pub message: String,
+    /// Character offset of the failing token, if the parser reported one
+    pub position: Option<usize>,
+    pub suggestions: Vec<String>,
+}
+
+/**
+ * A problem found in an expression that parses, which would make it fail or misbehave when
+ * evaluated.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ExpressionIssue {
+    /// `unknown_function`, `arity` or `type_mismatch`
+    pub kind: String,
+    /// The source of the offending sub-expression
+    pub expression: String,
+    pub message: String,
+}
+
+/**
+ * A field of an execution context that doesn't deserialize, reported by `validate_context`.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ContextIssue {
+    /// Where the field is in the context, like `variables.map.user.value.tags.value[1]`
+    pub path: String,
+    pub message: String,
+}
+
+// Word operators people carry over from other languages, and their CEL equivalents
+const WORD_OPERATORS: &[(&str, &str)] = &[("and", "&&"), ("or", "||"), ("not", "!")];
+
+/**
+ * Builds a `ParseFailure` from the parser error of the given expression by inspecting
+ * the source around the failing token.
+ */
+pub(crate) fn describe_parse_error(expression: &str, error: &str) -> ParseFailure {
+    let message = error.lines().next().unwrap_or_default().to_string();
+    let byte_position = error_position(&message);
+    let position = byte_position.map(|byte| char_offset(expression, byte));
+    let mut suggestions = Vec::new();
+
+    for word in words_outside_strings(expression) {
+        if let Some((_, operator)) = WORD_OPERATORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(word))
+        {
+            suggestions.push(format!(
+                "Did you mean `{}` instead of `{}`?",
+                operator, word
+            ));
+        }
+    }
+
+    if message.starts_with("Invalid token") {
+        if let Some(byte) = byte_position {
+            let rest = &expression[byte.min(expression.len())..];
+            let previous = expression[..byte.min(expression.len())].chars().last();
+            match rest.chars().next() {
+                Some(quote @ ('\'' | '"')) => suggestions.push(format!(
+                    "Unterminated string literal starting at column {}, add the closing {}",
+                    position.unwrap_or_default() + 1,
+                    quote
+                )),
+                Some('=') if previous == Some('=') => {
+                    suggestions.push("Use `==` for equality, `===` is not supported".to_string())
+                }
+                Some('=') => suggestions.push("Did you mean `==` instead of `=`?".to_string()),
+                Some('&') => suggestions.push("Did you mean `&&` instead of `&`?".to_string()),
+                Some('|') => suggestions.push("Did you mean `||` instead of `|`?".to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let (parens, brackets, braces) = bracket_balance(expression);
+    for (balance, open, close) in [(parens, '(', ')'), (brackets, '[', ']'), (braces, '{', '}')] {
+        if balance > 0 {
+            suggestions.push(format!("Missing closing `{}`", close));
+        } else if balance < 0 {
+            suggestions.push(format!(
+                "Unexpected `{}` without a matching `{}`",
+                close, open
+            ));
+        }
+    }
+
+    if suggestions.is_empty() && message.starts_with("Unrecognized EOF") {
+        suggestions.push("The expression ends unexpectedly, an operand may be missing".to_string());
+    }
+
+    ParseFailure {
+        message,
+        position,
+        suggestions,
+    }
+}
+
+// Extracts the byte offset from "... at 12" or "... found at 2:5"
+fn error_position(message: &str) -> Option<usize> {
+    let location = message.rsplit(" at ").next()?;
+    location.split(':').next()?.trim().parse().ok()
+}
+
+fn char_offset(expression: &str, byte: usize) -> usize {
+    expression
+        .char_indices()
+        .take_while(|(index, _)| *index < byte)
+        .count()
+}
+
+// Identifier-like words of the expression, skipping the contents of string literals
+fn words_outside_strings(expression: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start: Option<usize> = None;
+    for (index, c) in expression.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' {
+            start.get_or_insert(index);
+            continue;
+        }
+        if let Some(s) = start.take() {
+            words.push(&expression[s..index]);
+        }
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+        }
+    }
+    if let Some(s) = start {
+        words.push(&expression[s..]);
+    }
+    words
+}
+
+// Net count of unclosed (), [] and {} outside string literals
+fn bracket_balance(expression: &str) -> (i32, i32, i32) {
+    let (mut parens, mut brackets, mut braces) = (0, 0, 0);
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in expression.chars() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '(' => parens += 1,
+            ')' => parens -= 1,
+            '[' => brackets += 1,
+            ']' => brackets -= 1,
+            '{' => braces += 1,
+            '}' => braces -= 1,
+            _ => {}
+        }
+    }
+    (parens, brackets, braces)
+}
+
+// The type of a sub-expression, as far as it can be told without knowing the variables
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum StaticType {
+    Number,
+    String,
+    Bytes,
+    Bool,
+    List,
+    Map,
+    Null,
+    Unknown,
+}
+
+impl StaticType {
+    fn of(expr: &Expression) -> StaticType {
+        match expr {
+            Expression::Atom(Atom::Int(_) | Atom::UInt(_) | Atom::Float(_)) => StaticType::Number,
+            Expression::Atom(Atom::String(_)) => StaticType::String,
+            Expression::Atom(Atom::Bytes(_)) => StaticType::Bytes,
+            Expression::Atom(Atom::Bool(_)) => StaticType::Bool,
+            Expression::Atom(Atom::Null) => StaticType::Null,
+            Expression::List(_) => StaticType::List,
+            Expression::Map(_) => StaticType::Map,
+            Expression::Relation(..) | Expression::And(..) | Expression::Or(..) => StaticType::Bool,
+            Expression::Unary(UnaryOp::Not | UnaryOp::DoubleNot, _) => StaticType::Bool,
+            Expression::Unary(UnaryOp::Minus | UnaryOp::DoubleMinus, _) => StaticType::Number,
+            Expression::Arithmetic(left, op, right) => {
+                match arithmetic_type(StaticType::of(left), op, StaticType::of(right)) {
+                    Some(result) => result,
+                    None => StaticType::Unknown,
+                }
+            }
+            _ => StaticType::Unknown,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StaticType::Number => "number",
+            StaticType::String => "string",
+            StaticType::Bytes => "bytes",
+            StaticType::Bool => "bool",
+            StaticType::List => "list",
+            StaticType::Map => "map",
+            StaticType::Null => "null",
+            StaticType::Unknown => "unknown",
+        }
+    }
+}
+
+// The type of an arithmetic operation, None if the operand types don't support it
+fn arithmetic_type(left: StaticType, op: &ArithmeticOp, right: StaticType) -> Option<StaticType> {
+    match (left, op, right) {
+        (StaticType::Unknown, _, _) | (_, _, StaticType::Unknown) => Some(StaticType::Unknown),
+        (StaticType::Number, _, StaticType::Number) => Some(StaticType::Number),
+        (StaticType::String, ArithmeticOp::Add, StaticType::String) => Some(StaticType::String),
+        (StaticType::Bytes, ArithmeticOp::Add, StaticType::Bytes) => Some(StaticType::Bytes),
+        (StaticType::List, ArithmeticOp::Add, StaticType::List) => Some(StaticType::List),
+        _ => None,
+    }
+}
+
+/**
+ * Lists every issue of a parsed expression that can be detected without evaluating it: calls
+ * of unknown functions, built-in calls with the wrong number of arguments, and arithmetic and
+ * negations of operands whose types make them fail, like `'a' + 1` or `-'a'`. Operators that
+ * take any operand, like `!` and `&&` testing truthiness, or orderings of mismatched types
+ * evaluating to false, aren't reported. Host functions are only known at evaluation time, so
+ * calls on `device` and `computed` are not checked.
+ */
+pub(crate) fn expression_issues(expr: &Expression) -> Vec<ExpressionIssue> {
+    let mut issues = Vec::new();
+    visit_expressions(expr, &mut |expr| {
+        let issue = |kind: &str, message: String| ExpressionIssue {
+            kind: kind.to_string(),
+            expression: expression_to_source(expr),
+            message,
+        };
+        match expr {
+            Expression::FunctionCall(function, target, args) => {
+                let Expression::Ident(name) = function.as_ref() else {
+                    return;
+                };
+                let host_call = matches!(
+                    target.as_deref(),
+                    Some(Expression::Ident(namespace))
+                        if namespace.as_str() == "device" || namespace.as_str() == "computed"
+                );
+                if host_call {
+                    return;
+                }
+                if !SUPPORTED_FUNCTIONS.contains(&name.as_str())
+                    && !STANDARD_FUNCTIONS.contains(&name.as_str())
+                {
+                    issues.push(issue(
+                        "unknown_function",
+                        format!("Unknown function `{}`", name),
+                    ));
+                } else if let Some(message) = builtin_arity_error(name, target, args) {
+                    issues.push(issue("arity", format!("`{}` {}", name, message)));
+                }
+            }
+            Expression::Arithmetic(left, op, right) => {
+                let (left, right) = (StaticType::of(left), StaticType::of(right));
+                if arithmetic_type(left, op, right).is_none() {
+                    issues.push(issue(
+                        "type_mismatch",
+                        format!(
+                            "Arithmetic on a {} and a {} is not supported",
+                            left.name(),
+                            right.name()
+                        ),
+                    ));
+                }
+            }
+            Expression::Unary(UnaryOp::Minus | UnaryOp::DoubleMinus, operand) => {
+                // Negating null evaluates to null
+                let operand = StaticType::of(operand);
+                if !matches!(
+                    operand,
+                    StaticType::Unknown | StaticType::Number | StaticType::Null
+                ) {
+                    issues.push(issue(
+                        "type_mismatch",
+                        format!("Expected a number operand but got a {}", operand.name()),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    });
+    issues
+}
+
+/**
+ * Lists the fields of an execution context that don't deserialize, each at the deepest path
+ * it can be pinned to: a mis-tagged element of a nested list is reported at the element rather
+ * than at the variable holding the list. The expression isn't checked.
+ */
+pub(crate) fn context_issues(context: &Json) -> Vec<ContextIssue> {
+    let mut issues = Vec::new();
+    let Json::Object(fields) = context else {
+        return vec![issue("", "the context must be an object")];
+    };
+    match fields.get("variables") {
+        Some(Json::Object(variables)) => match variables.get("map") {
+            Some(Json::Object(map)) => {
+                for (name, value) in map {
+                    value_issues(value, &format!("variables.map.{}", name), &mut issues);
+                }
+            }
+            _ => issues.push(issue("variables.map", "expected an object of variables")),
+        },
+        Some(_) => issues.push(issue("variables", "expected an object with a `map`")),
+        None => {}
+    }
+    for namespace in ["device", "computed"] {
+        match fields.get(namespace) {
+            Some(Json::Object(functions)) => {
+                for (name, args) in functions {
+                    let path = format!("{}.{}", namespace, name);
+                    match args {
+                        Json::Array(args) => args.iter().enumerate().for_each(|(index, arg)| {
+                            value_issues(arg, &format!("{}[{}]", path, index), &mut issues)
+                        }),
+                        _ => issues.push(issue(&path, "expected a list of declared arguments")),
+                    }
+                }
+            }
+            Some(Json::Null) | None => {}
+            Some(_) => issues.push(issue(namespace, "expected an object of functions")),
+        }
+    }
+    for key in ["prior_results", "priorResults"] {
+        if let Some(results) = fields.get(key) {
+            match results {
+                Json::Object(results) => {
+                    for (id, value) in results {
+                        value_issues(value, &format!("{}.{}", key, id), &mut issues);
+                    }
+                }
+                _ => issues.push(issue(key, "expected an object of results")),
+            }
+        }
+    }
+    if let Some(options) = fields.get("options").filter(|options| !options.is_null()) {
+        if let Err(err) = deserialize::<EvaluationOptions>(options) {
+            issues.push(issue("options", &err));
+        }
+    }
+    issues
+}
+
+// Adds the issues of a `PassableValue`, looking into lists and maps for the elements that
+// don't deserialize before reporting the collection itself
+fn value_issues(value: &Json, path: &str, issues: &mut Vec<ContextIssue>) {
+    let Err(err) = deserialize::<PassableValue>(value) else {
+        return;
+    };
+    let found = issues.len();
+    match (value.get("type").and_then(Json::as_str), value.get("value")) {
+        (Some("list"), Some(Json::Array(items))) => {
+            for (index, item) in items.iter().enumerate() {
+                value_issues(item, &format!("{}.value[{}]", path, index), issues);
+            }
+        }
+        (Some("map"), Some(Json::Object(entries))) => {
+            for (key, entry) in entries {
+                value_issues(entry, &format!("{}.value.{}", path, key), issues);
+            }
+        }
+        _ => {}
+    }
+    if issues.len() == found {
+        issues.push(issue(path, &err));
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(value: &Json) -> Result<T, String> {
+    T::deserialize(value).map_err(|err| err.to_string())
+}
+
+fn issue(path: &str, message: &str) -> ContextIssue {
+    ContextIssue {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
@@ -0,0 +1,193 @@
+use crate::ast::{expression_to_source, relation_operand_to_source, relation_operator};
+use crate::models::PassableValue;
+use cel_parser::{Expression, UnaryOp};
+
+/**
+ * Renders a plain-English explanation of an evaluated decision, citing the resolved value of
+ * every condition the decision depends on, e.g.
+ * `matched because user.credits (5) > 0 and device.trial (true)`.
+ * @param expr The parsed expression, before null-safety transformations
+ * @param result The result of the whole expression
+ * @param value_of The value a sub-expression had in the evaluation, `None` if it wasn't evaluated
+ */
+pub(crate) fn explain(
+    expr: &Expression,
+    result: &PassableValue,
+    value_of: &mut impl FnMut(&Expression) -> Option<PassableValue>,
+) -> String {
+    match result {
+        PassableValue::Bool(matched) => format!(
+            "{} because {}",
+            if *matched { "matched" } else { "did not match" },
+            reasons(expr, value_of).join(" and ")
+        ),
+        other => format!("evaluated to {}", describe_value(other)),
+    }
+}
+
+/**
+ * The conditions that decided the value of a boolean expression: both operands of a true `&&`
+ * or a false `||`, otherwise the operand that short-circuited it.
+ */
+fn reasons(
+    expr: &Expression,
+    value_of: &mut impl FnMut(&Expression) -> Option<PassableValue>,
+) -> Vec<String> {
+    match expr {
+        Expression::And(left, right) => {
+            if !is_true(&value_of(left)) {
+                reasons(left, value_of)
+            } else if !is_true(&value_of(right)) {
+                reasons(right, value_of)
+            } else {
+                [reasons(left, value_of), reasons(right, value_of)].concat()
+            }
+        }
+        Expression::Or(left, right) => {
+            if is_true(&value_of(left)) {
+                reasons(left, value_of)
+            } else if is_true(&value_of(right)) {
+                reasons(right, value_of)
+            } else {
+                [reasons(left, value_of), reasons(right, value_of)].concat()
+            }
+        }
+        Expression::Ternary(condition, if_true, if_false) => {
+            let branch = if is_true(&value_of(condition)) {
+                if_true
+            } else {
+                if_false
+            };
+            [reasons(condition, value_of), reasons(branch, value_of)].concat()
+        }
+        Expression::Unary(UnaryOp::Not, operand) => {
+            vec![format!("not {}", condition(operand, value_of))]
+        }
+        _ => vec![condition(expr, value_of)],
+    }
+}
+
+/**
+ * Wraps the sub-expressions whose values `explain` cites in `observeValue`, so an evaluation of
+ * the wrapped expression records them: the operands of the boolean operators, the conditions of
+ * ternaries, and the conditions cited, with the operands of relations.
+ * @param expr The parsed expression, before null-safety transformations
+ * @param observe Whether the value of the expression itself is cited
+ * @param observed The observed sub-expressions, with the numbers they are recorded under
+ */
+pub(crate) fn observe_explained(
+    expr: Expression,
+    observe: bool,
+    observed: &mut Vec<(Expression, usize)>,
+) -> Expression {
+    let source = observe.then(|| expr.clone());
+    let expr = match expr {
+        Expression::And(left, right) => Expression::And(
+            Box::new(observe_explained(*left, true, observed)),
+            Box::new(observe_explained(*right, true, observed)),
+        ),
+        Expression::Or(left, right) => Expression::Or(
+            Box::new(observe_explained(*left, true, observed)),
+            Box::new(observe_explained(*right, true, observed)),
+        ),
+        Expression::Ternary(condition, if_true, if_false) => Expression::Ternary(
+            Box::new(observe_explained(*condition, true, observed)),
+            Box::new(observe_explained(*if_true, false, observed)),
+            Box::new(observe_explained(*if_false, false, observed)),
+        ),
+        Expression::Unary(UnaryOp::Not, operand) => Expression::Unary(
+            UnaryOp::Not,
+            Box::new(observe_explained(*operand, true, observed)),
+        ),
+        // A cited condition, whose value is cited even as a branch of a ternary
+        expr => return observe_condition(expr.clone(), expr, observed),
+    };
+    match source {
+        Some(source) => observe_condition(source, expr, observed),
+        None => expr,
+    }
+}
+
+// Wraps a sub-expression in `observeValue`, numbering its source and the operands of a relation
+fn observe_condition(
+    source: Expression,
+    expr: Expression,
+    observed: &mut Vec<(Expression, usize)>,
+) -> Expression {
+    // Each condition takes three numbers, the two following its own for the operands
+    let number = observed.last().map_or(0, |(_, number)| number / 3 * 3 + 3);
+    if let Expression::Relation(left, _, right) = &source {
+        observed.push((left.as_ref().clone(), number + 1));
+        observed.push((right.as_ref().clone(), number + 2));
+    }
+    observed.push((source, number));
+    crate::observed(number, expr)
+}
+
+// A single condition with its resolved operands, like `user.credits (5) > 0`
+fn condition(
+    expr: &Expression,
+    value_of: &mut impl FnMut(&Expression) -> Option<PassableValue>,
+) -> String {
+    match expr {
+        Expression::Relation(left, op, right) => format!(
+            "{}{} {} {}{}",
+            relation_operand_to_source(left),
+            operand_value(left, value_of),
+            relation_operator(op),
+            relation_operand_to_source(right),
+            operand_value(right, value_of)
+        ),
+        Expression::Atom(_) => expression_to_source(expr),
+        _ => format!(
+            "{}{}",
+            expression_to_source(expr),
+            operand_value(expr, value_of)
+        ),
+    }
+}
+
+// The resolved value of an operand, omitted for literals and operands that weren't evaluated
+fn operand_value(
+    operand: &Expression,
+    value_of: &mut impl FnMut(&Expression) -> Option<PassableValue>,
+) -> String {
+    match (operand, value_of(operand)) {
+        (Expression::Atom(_), _) | (_, None) => String::new(),
+        (_, Some(value)) => format!(" ({})", describe_value(&value)),
+    }
+}
+
+fn is_true(value: &Option<PassableValue>) -> bool {
+    matches!(value, Some(PassableValue::Bool(true)))
+}
+
+// Renders a value the way it would be written in an expression
+pub(crate) fn describe_value(value: &PassableValue) -> String {
+    match value {
+        PassableValue::List(list) => format!(
+            "[{}]",
+            list.iter()
+                .map(describe_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        PassableValue::PMap(map) => {
+            let mut entries: Vec<_> = map
+                .iter()
+                .map(|(key, value)| format!("{:?}: {}", key, describe_value(value)))
+                .collect();
+            entries.sort();
+            format!("{{{}}}", entries.join(", "))
+        }
+        PassableValue::Function(name, _) => format!("{}()", name),
+        PassableValue::Int(i) => i.to_string(),
+        PassableValue::UInt(u) => format!("{}u", u),
+        PassableValue::Float(f) => format!("{:?}", f),
+        PassableValue::String(s) => format!("{:?}", s),
+        PassableValue::Bytes(bytes) => format!("b{:?}", String::from_utf8_lossy(bytes)),
+        PassableValue::Bool(b) => b.to_string(),
+        PassableValue::Timestamp(t) => t.to_string(),
+        PassableValue::Null => "null".to_string(),
+    }
+}
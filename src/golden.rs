@@ -0,0 +1,90 @@
+use crate::explain::describe_value;
+use crate::models::PassableValue;
+use std::collections::BTreeSet;
+
+/**
+ * Describes how the actual result of a golden case differs from the expected one, one line per
+ * difference, each naming the path to the differing value from `result`, e.g.
+ * `result.plans[1]: expected "pro", got "free"`. Equal results have no differences.
+ */
+pub(crate) fn result_differences(
+    expected: &Result<PassableValue, String>,
+    actual: &Result<PassableValue, String>,
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    match (expected, actual) {
+        (Ok(expected), Ok(actual)) => {
+            value_differences("result", expected, actual, &mut differences)
+        }
+        (Err(expected), Err(actual)) if expected != actual => {
+            differences.push(format!("error: expected {:?}, got {:?}", expected, actual))
+        }
+        (Err(_), Err(_)) => {}
+        (Ok(expected), Err(actual)) => differences.push(format!(
+            "result: expected {}, got the error {:?}",
+            describe_value(expected),
+            actual
+        )),
+        (Err(expected), Ok(actual)) => differences.push(format!(
+            "error: expected {:?}, got the result {}",
+            expected,
+            describe_value(actual)
+        )),
+    }
+    differences
+}
+
+fn value_differences(
+    path: &str,
+    expected: &PassableValue,
+    actual: &PassableValue,
+    differences: &mut Vec<String>,
+) {
+    match (expected, actual) {
+        (PassableValue::PMap(expected), PassableValue::PMap(actual)) => {
+            let keys: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+            for key in keys {
+                let path = format!("{}.{}", path, key);
+                match (expected.get(key), actual.get(key)) {
+                    (Some(expected), Some(actual)) => {
+                        value_differences(&path, expected, actual, differences)
+                    }
+                    (Some(expected), None) => differences.push(format!(
+                        "{}: expected {}, missing",
+                        path,
+                        describe_value(expected)
+                    )),
+                    (None, Some(actual)) => {
+                        differences.push(format!("{}: unexpected {}", path, describe_value(actual)))
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        (PassableValue::List(expected), PassableValue::List(actual)) => {
+            if expected.len() != actual.len() {
+                differences.push(format!(
+                    "{}: expected {} elements, got {}",
+                    path,
+                    expected.len(),
+                    actual.len()
+                ));
+            }
+            for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                value_differences(
+                    &format!("{}[{}]", path, index),
+                    expected,
+                    actual,
+                    differences,
+                );
+            }
+        }
+        (expected, actual) if expected != actual => differences.push(format!(
+            "{}: expected {}, got {}",
+            path,
+            describe_value(expected),
+            describe_value(actual)
+        )),
+        _ => {}
+    }
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+
+use crate::EvalError;
+
+/// The locales error messages are translated to, English first as the fallback
+pub(crate) const LOCALES: &[&str] = &["en", "es", "de"];
+
+/**
+ * The errors reported by Superscript itself, each with a stable code hosts can match on and a
+ * message per locale. Errors raised by the CEL interpreter keep its English messages.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorMessage {
+    InvalidContext,
+    InvalidAstContext,
+    InvalidBaseContext,
+    InvalidOverrides,
+    InvalidProtobuf,
+    InvalidVariables,
+    InvalidData,
+    InvalidCharacter,
+    CompileFailed,
+    ExpressionTooDeep,
+    BindingCompileFailed,
+    BindingFailed,
+    InlineCompileFailed,
+    InvalidCompareFunction,
+    HostCallLimit,
+    UnsupportedHostCapability,
+    StringTooLong,
+    RequiresHost,
+    ReturnTypeMismatch,
+    VariableTypeMismatch,
+    UndeclaredDefaultResult,
+    NotCaptured,
+    NotAMap,
+    UnknownErrorCode,
+}
+
+impl ErrorMessage {
+    const ALL: &'static [ErrorMessage] = &[
+        ErrorMessage::InvalidContext,
+        ErrorMessage::InvalidAstContext,
+        ErrorMessage::InvalidBaseContext,
+        ErrorMessage::InvalidOverrides,
+        ErrorMessage::InvalidProtobuf,
+        ErrorMessage::InvalidVariables,
+        ErrorMessage::InvalidData,
+        ErrorMessage::InvalidCharacter,
+        ErrorMessage::CompileFailed,
+        ErrorMessage::ExpressionTooDeep,
+        ErrorMessage::BindingCompileFailed,
+        ErrorMessage::BindingFailed,
+        ErrorMessage::InlineCompileFailed,
+        ErrorMessage::InvalidCompareFunction,
+        ErrorMessage::HostCallLimit,
+        ErrorMessage::UnsupportedHostCapability,
+        ErrorMessage::StringTooLong,
+        ErrorMessage::RequiresHost,
+        ErrorMessage::ReturnTypeMismatch,
+        ErrorMessage::VariableTypeMismatch,
+        ErrorMessage::UndeclaredDefaultResult,
+        ErrorMessage::NotCaptured,
+        ErrorMessage::NotAMap,
+        ErrorMessage::UnknownErrorCode,
+    ];
+
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            ErrorMessage::InvalidContext => "invalid_context",
+            ErrorMessage::InvalidAstContext => "invalid_ast_context",
+            ErrorMessage::InvalidBaseContext => "invalid_base_context",
+            ErrorMessage::InvalidOverrides => "invalid_overrides",
+            ErrorMessage::InvalidProtobuf => "invalid_protobuf",
+            ErrorMessage::InvalidVariables => "invalid_variables",
+            ErrorMessage::InvalidData => "invalid_data",
+            ErrorMessage::InvalidCharacter => "invalid_character",
+            ErrorMessage::CompileFailed => "compile_failed",
+            ErrorMessage::ExpressionTooDeep => "expression_too_deep",
+            ErrorMessage::BindingCompileFailed => "binding_compile_failed",
+            ErrorMessage::BindingFailed => "binding_failed",
+            ErrorMessage::InlineCompileFailed => "inline_compile_failed",
+            ErrorMessage::InvalidCompareFunction => "invalid_compare_function",
+            ErrorMessage::HostCallLimit => "host_call_limit",
+            ErrorMessage::UnsupportedHostCapability => "unsupported_host_capability",
+            ErrorMessage::StringTooLong => "string_too_long",
+            ErrorMessage::RequiresHost => "requires_host",
+            ErrorMessage::ReturnTypeMismatch => "return_type_mismatch",
+            ErrorMessage::VariableTypeMismatch => "variable_type_mismatch",
+            ErrorMessage::UndeclaredDefaultResult => "undeclared_default_result",
+            ErrorMessage::NotCaptured => "not_captured",
+            ErrorMessage::NotAMap => "not_a_map",
+            ErrorMessage::UnknownErrorCode => "unknown_error_code",
+        }
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<ErrorMessage> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|message| message.code() == code)
+    }
+
+    // The message templates in the order of `LOCALES`, `{}` standing for each argument
+    fn templates(self) -> [&'static str; 3] {
+        match self {
+            ErrorMessage::InvalidContext => [
+                "Invalid execution context JSON: {}",
+                "JSON del contexto de ejecución no válido: {}",
+                "Ungültiges JSON des Ausführungskontexts: {}",
+            ],
+            ErrorMessage::InvalidAstContext => [
+                "Invalid definition for AST Execution",
+                "Definición no válida para la ejecución del AST",
+                "Ungültige Definition für die AST-Ausführung",
+            ],
+            ErrorMessage::InvalidBaseContext => [
+                "Invalid base context JSON: {}",
+                "JSON del contexto base no válido: {}",
+                "Ungültiges JSON des Basiskontexts: {}",
+            ],
+            ErrorMessage::InvalidOverrides => [
+                "Invalid overrides context JSON: {}",
+                "JSON de las sobrescrituras no válido: {}",
+                "Ungültiges JSON der Überschreibungen: {}",
+            ],
+            ErrorMessage::InvalidProtobuf => [
+                "Invalid execution context protobuf: {}",
+                "Protobuf del contexto de ejecución no válido: {}",
+                "Ungültiges Protobuf des Ausführungskontexts: {}",
+            ],
+            ErrorMessage::InvalidVariables => [
+                "Invalid variables JSON: {}",
+                "JSON de las variables no válido: {}",
+                "Ungültiges JSON der Variablen: {}",
+            ],
+            ErrorMessage::InvalidData => [
+                "Invalid JSON data: {}",
+                "Datos JSON no válidos: {}",
+                "Ungültige JSON-Daten: {}",
+            ],
+            ErrorMessage::InvalidCharacter => [
+                "Invalid character {} at position {} of the expression",
+                "Carácter no válido {} en la posición {} de la expresión",
+                "Ungültiges Zeichen {} an Position {} des Ausdrucks",
+            ],
+            ErrorMessage::CompileFailed => [
+                "Failed to compile expression",
+                "No se pudo compilar la expresión",
+                "Der Ausdruck konnte nicht kompiliert werden",
+            ],
+            ErrorMessage::ExpressionTooDeep => [
+                "The expression is nested deeper than {} levels",
+                "La expresión está anidada a más de {} niveles",
+                "Der Ausdruck ist tiefer als {} Ebenen verschachtelt",
+            ],
+            ErrorMessage::BindingCompileFailed => [
+                "Failed to compile binding {}",
+                "No se pudo compilar el enlace {}",
+                "Die Bindung {} konnte nicht kompiliert werden",
+            ],
+            ErrorMessage::BindingFailed => [
+                "Failed to evaluate binding {}: {}",
+                "No se pudo evaluar el enlace {}: {}",
+                "Die Bindung {} konnte nicht ausgewertet werden: {}",
+            ],
+            ErrorMessage::InlineCompileFailed => [
+                "Failed to compile the inline definition of {}",
+                "No se pudo compilar la definición en línea de {}",
+                "Die Inline-Definition von {} konnte nicht kompiliert werden",
+            ],
+            ErrorMessage::InvalidCompareFunction => [
+                "The comparison function {} of the custom type {} is not a device or computed function",
+                "La función de comparación {} del tipo personalizado {} no es una función device o computed",
+                "Die Vergleichsfunktion {} des benutzerdefinierten Typs {} ist keine device- oder computed-Funktion",
+            ],
+            ErrorMessage::HostCallLimit => [
+                "ResourceExhausted: the evaluation exceeded the limit of {} host calls",
+                "ResourceExhausted: la evaluación superó el límite de {} llamadas al host",
+                "ResourceExhausted: die Auswertung hat das Limit von {} Host-Aufrufen überschritten",
+            ],
+            ErrorMessage::UnsupportedHostCapability => [
+                "UnsupportedHostCapability: the host does not provide {}",
+                "UnsupportedHostCapability: el host no proporciona {}",
+                "UnsupportedHostCapability: der Host stellt {} nicht bereit",
+            ],
+            ErrorMessage::StringTooLong => [
+                "ResourceExhausted: a string of {} characters exceeds the limit of {}",
+                "ResourceExhausted: una cadena de {} caracteres supera el límite de {}",
+                "ResourceExhausted: eine Zeichenkette mit {} Zeichen überschreitet das Limit von {}",
+            ],
+            ErrorMessage::RequiresHost => [
+                "RequiresHost: the evaluation needs the host property {}",
+                "RequiresHost: la evaluación necesita la propiedad del host {}",
+                "RequiresHost: die Auswertung benötigt die Host-Eigenschaft {}",
+            ],
+            ErrorMessage::ReturnTypeMismatch => [
+                "Declared return type is {} but the host returned {}",
+                "El tipo de retorno declarado es {} pero el host devolvió {}",
+                "Der deklarierte Rückgabetyp ist {}, aber der Host hat {} zurückgegeben",
+            ],
+            ErrorMessage::VariableTypeMismatch => [
+                "Declared type of {} is {} but its value is {}",
+                "El tipo declarado de {} es {} pero su valor es {}",
+                "Der deklarierte Typ von {} ist {}, aber sein Wert ist {}",
+            ],
+            ErrorMessage::UndeclaredDefaultResult => [
+                "The default result {} is for no declared device or computed function",
+                "El resultado predeterminado {} no es de ninguna función device o computed declarada",
+                "Das Standardergebnis {} gehört zu keiner deklarierten device- oder computed-Funktion",
+            ],
+            ErrorMessage::NotCaptured => [
+                "No value was captured for {}",
+                "No se capturó ningún valor para {}",
+                "Für {} wurde kein Wert erfasst",
+            ],
+            ErrorMessage::NotAMap => [
+                "Cannot set {}, {} is not a map",
+                "No se puede asignar {}, {} no es un mapa",
+                "{} kann nicht gesetzt werden, {} ist keine Map",
+            ],
+            ErrorMessage::UnknownErrorCode => [
+                "Unknown error code: {}",
+                "Código de error desconocido: {}",
+                "Unbekannter Fehlercode: {}",
+            ],
+        }
+    }
+
+    /**
+     * The message template for the locale, falling back to English for unknown locales. Regional
+     * locales like `es-MX` use the template of their language.
+     */
+    pub(crate) fn template(self, locale: Option<&str>) -> &'static str {
+        let language = locale
+            .and_then(|locale| locale.split(['-', '_']).next())
+            .unwrap_or_default();
+        let index = LOCALES
+            .iter()
+            .position(|supported| supported.eq_ignore_ascii_case(language))
+            .unwrap_or(0);
+        self.templates()[index]
+    }
+
+    /**
+     * The message for the locale with each `{}` of its template replaced by the next argument.
+     */
+    pub(crate) fn format(self, locale: Option<&str>, args: &[&dyn fmt::Display]) -> String {
+        let mut args = args.iter();
+        let mut parts = self.template(locale).split("{}");
+        let mut message = parts.next().unwrap_or_default().to_string();
+        for part in parts {
+            if let Some(arg) = args.next() {
+                message.push_str(&arg.to_string());
+            }
+            message.push_str(part);
+        }
+        message
+    }
+
+    /**
+     * The message for the locale as an `EvalError`, carrying the code along with the message.
+     */
+    pub(crate) fn error(self, locale: Option<&str>, args: &[&dyn fmt::Display]) -> EvalError {
+        EvalError::Localized {
+            code: self.code().to_string(),
+            message: self.format(locale, args),
+        }
+    }
+
+    /**
+     * The error whose template in the locale the message was formatted from, if any. The message
+     * may be wrapped by the interpreter, like `Error executing function 'requires': ...`.
+     */
+    pub(crate) fn matching(message: &str, locale: Option<&str>) -> Option<ErrorMessage> {
+        Self::ALL.iter().copied().find(|error| {
+            let mut rest = message;
+            error
+                .template(locale)
+                .split("{}")
+                .all(|part| match rest.find(part) {
+                    Some(index) => {
+                        rest = &rest[index + part.len()..];
+                        true
+                    }
+                    None => false,
+                })
+        })
+    }
+}
+
+/**
+ * The `locale` option of a serialized execution context, read leniently so errors about a
+ * context that doesn't deserialize can still be localized.
+ */
+pub(crate) fn requested_locale(definition: &str) -> Option<String> {
+    let context: serde_json::Value = serde_json::from_str(definition).ok()?;
+    context
+        .get("options")?
+        .get("locale")?
+        .as_str()
+        .map(str::to_string)
+}
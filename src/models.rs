@@ -1,8 +1,10 @@
 use crate::DisplayableValue;
 use cel_interpreter::objects::{Key, Map};
 use cel_interpreter::Value;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use indexmap::{IndexMap, IndexSet};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::sync::Arc;
 
@@ -12,9 +14,280 @@ pub(crate) struct ExecutionContext {
     pub(crate) expression: String,
     pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
     pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    #[serde(default)]
+    pub(crate) options: EvaluationOptions,
+    /// Results of previously evaluated rules, keyed by rule id and exposed as `results`
+    #[serde(default, alias = "priorResults")]
+    pub(crate) prior_results: HashMap<String, PassableValue>,
+    /// Named expressions, typically host calls with fixed arguments, evaluated once and exposed
+    /// as variables, e.g. `{"purchases": "device.eventCount(\"purchase\")"}`
+    #[serde(default)]
+    pub(crate) bindings: HashMap<String, String>,
+    /// Host functions read as namespaces, e.g. `device.network`: one host call returns a map
+    /// and `device.network.type` reads a field of it
+    #[serde(default)]
+    pub(crate) namespaces: Vec<String>,
+    /// Host types compared through a host function, keyed by type name
+    #[serde(default, alias = "customTypes")]
+    pub(crate) custom_types: HashMap<String, CustomType>,
+    /// Declared types of variables, keyed by dotted path like `user.credits`, using the
+    /// `PassableValue` type names. Missing variables of a declared type read as its zero value
+    #[serde(default, alias = "variableTypes")]
+    pub(crate) variable_types: HashMap<String, String>,
+    /// Expressions evaluated in place of calls to host functions, keyed by qualified name, e.g.
+    /// `{"computed.isPro": "user.plan == 'pro'"}`, reading the call's arguments as `args[0]`, `args[1]`, ...
+    #[serde(default, alias = "inlineFunctions")]
+    pub(crate) inline_functions: HashMap<String, String>,
 }
 
+/// A host type, like money maps, whose comparisons are delegated to a host function, as CEL
+/// can't overload operators
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct CustomType {
+    /// The host function comparing two values, e.g. `computed.compareMoney`, returning a
+    /// negative, zero or positive int
+    pub(crate) compare: String,
+    /// The variables, e.g. `user.balance`, and host functions, e.g. `computed.money`, whose
+    /// values have the type
+    #[serde(default)]
+    pub(crate) values: Vec<String>,
+}
+
+/// A partial execution context without an expression, as layered by
+/// `evaluate_with_base_and_overrides`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub(crate) struct ContextLayer {
+    #[serde(default)]
+    pub(crate) variables: PassableMap,
+    pub(crate) computed: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) device: Option<HashMap<String, Vec<PassableValue>>>,
+    pub(crate) options: Option<EvaluationOptions>,
+    #[serde(default, alias = "priorResults")]
+    pub(crate) prior_results: HashMap<String, PassableValue>,
+    #[serde(default)]
+    pub(crate) bindings: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) namespaces: Vec<String>,
+    #[serde(default, alias = "customTypes")]
+    pub(crate) custom_types: HashMap<String, CustomType>,
+    #[serde(default, alias = "variableTypes")]
+    pub(crate) variable_types: HashMap<String, String>,
+    #[serde(default, alias = "inlineFunctions")]
+    pub(crate) inline_functions: HashMap<String, String>,
+}
+
+impl ContextLayer {
+    /// Layers the overrides on top of this context: variables are deep-merged, host functions,
+    /// prior results, bindings, custom types, variable types and inline functions are merged by
+    /// name, namespaces are combined, and options are replaced as a whole
+    pub(crate) fn merged_with(self, overrides: ContextLayer) -> ContextLayer {
+        let mut prior_results = self.prior_results;
+        prior_results.extend(overrides.prior_results);
+        let mut bindings = self.bindings;
+        bindings.extend(overrides.bindings);
+        let mut custom_types = self.custom_types;
+        custom_types.extend(overrides.custom_types);
+        let mut variable_types = self.variable_types;
+        variable_types.extend(overrides.variable_types);
+        let mut inline_functions = self.inline_functions;
+        inline_functions.extend(overrides.inline_functions);
+        let mut namespaces = self.namespaces;
+        for namespace in overrides.namespaces {
+            if !namespaces.contains(&namespace) {
+                namespaces.push(namespace);
+            }
+        }
+        ContextLayer {
+            variables: self.variables.merged_with(overrides.variables),
+            computed: merge_functions(self.computed, overrides.computed),
+            device: merge_functions(self.device, overrides.device),
+            options: overrides.options.or(self.options),
+            prior_results,
+            bindings,
+            namespaces,
+            custom_types,
+            variable_types,
+            inline_functions,
+        }
+    }
+
+    pub(crate) fn into_execution_context(self, expression: String) -> ExecutionContext {
+        ExecutionContext {
+            variables: self.variables,
+            expression,
+            computed: self.computed,
+            device: self.device,
+            options: self.options.unwrap_or_default(),
+            prior_results: self.prior_results,
+            bindings: self.bindings,
+            namespaces: self.namespaces,
+            custom_types: self.custom_types,
+            variable_types: self.variable_types,
+            inline_functions: self.inline_functions,
+        }
+    }
+}
+
+fn merge_functions(
+    base: Option<HashMap<String, Vec<PassableValue>>>,
+    overrides: Option<HashMap<String, Vec<PassableValue>>>,
+) -> Option<HashMap<String, Vec<PassableValue>>> {
+    match (base, overrides) {
+        (Some(mut base), Some(overrides)) => {
+            base.extend(overrides);
+            Some(base)
+        }
+        (base, overrides) => overrides.or(base),
+    }
+}
+
+/// A single host property lookup sent to the host as part of a batch
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HostRequest {
+    /// Identifier the host uses to key the result of this request
+    pub id: String,
+    /// Either `device` or `computed`
+    pub kind: String,
+    pub name: String,
+    pub args: Vec<PassableValue>,
+}
+
+/// The outcome of an evaluation with `no_host_calls` that reached a host property
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum HostRequirement {
+    /// The first host property the evaluation needed, like `device.daysSince`
+    RequiresHost { property: String },
+}
+
+/// Optional evaluation settings, all disabled by default. Like the fields of the context, each
+/// option is named in snake_case and also accepts its camelCase spelling
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct EvaluationOptions {
+    /// Declared return types of host functions, keyed by `device.<name>` or `computed.<name>`,
+    /// using the `PassableValue` type names (`int`, `string`, `bool`, ...)
+    #[serde(alias = "returnTypes")]
+    pub return_types: HashMap<String, String>,
+    /// Results of host functions used when the host fails to resolve them or returns null,
+    /// keyed by `device.<name>` or `computed.<name>`
+    #[serde(alias = "defaultResults")]
+    pub default_results: HashMap<String, PassableValue>,
+    /// Fail the evaluation when a host function returns a value that doesn't match its
+    /// declared return type, instead of coercing it
+    #[serde(alias = "strictReturnTypes")]
+    pub strict_return_types: bool,
+    /// Fail the evaluation when a variable declared in `variableTypes` holds a value of another
+    /// type, instead of reading it as it is
+    #[serde(alias = "strictVariableTypes")]
+    pub strict_variable_types: bool,
+    /// Functions the expression needs the host to provide, checked like `hasFn` before evaluating
+    pub requires: Vec<String>,
+    /// What the evaluation returns when a required function is not provided
+    #[serde(alias = "unsupportedCapability")]
+    pub unsupported_capability: CapabilityFallback,
+    /// Treat empty or whitespace-only strings returned by host functions as null
+    #[serde(alias = "trimToNull")]
+    pub trim_to_null: bool,
+    /// Maximum number of host calls a single evaluation may make, memoized calls excluded
+    #[serde(alias = "maxHostCalls")]
+    pub max_host_calls: Option<usize>,
+    /// Maximum number of characters of the strings concatenated or returned by host functions
+    /// during an evaluation
+    #[serde(alias = "maxStringLength")]
+    pub max_string_length: Option<usize>,
+    /// Fail calls to deprecated functions instead of only reporting them as warnings
+    #[serde(alias = "strictDeprecations")]
+    pub strict_deprecations: bool,
+    /// Serialize map results with their keys in the order they appear in the expression's map
+    /// literals, instead of an arbitrary order. Maps don't keep an order while evaluating, so
+    /// this only orders the JSON results of `evaluate_with_context`, `evaluate_with_resolver`
+    /// and `PreparedEvaluator`, and other entry points ignore it
+    #[serde(alias = "orderedMaps")]
+    pub ordered_maps: bool,
+    /// How `/` divides two integers
+    #[serde(alias = "integerDivision")]
+    pub integer_division: IntegerDivision,
+    /// The current time in seconds since the Unix epoch, used by `ageInDays` instead of the
+    /// system clock
+    pub now: Option<i64>,
+    /// Evaluate sub-expressions made only of literals, like `60 * 60 * 24`, once when the
+    /// expression is prepared instead of on every evaluation
+    #[serde(alias = "foldConstants")]
+    pub fold_constants: bool,
+    /// Reuse the result of a previous evaluation of the same context, by fingerprint, for this
+    /// many milliseconds instead of evaluating it and calling the host again
+    #[serde(alias = "resultCacheTtlMs")]
+    pub result_cache_ttl_ms: Option<u64>,
+    /// Host functions too costly to call unless the evaluation reaches them, like
+    /// `computed.fetchOffers`. Bindings calling them are evaluated where they are read instead of
+    /// before the expression
+    pub expensive: Vec<String>,
+    /// The locale of the error messages, e.g. `es`, English by default
+    pub locale: Option<String>,
+    /// Host results captured by `evaluate_capturing_snapshot`, keyed like
+    /// `device.daysSince:["install"]`, answering the host calls instead of the host
+    pub replay: Option<HashMap<String, PassableValue>>,
+    /// How strings are ordered by `<`, `<=`, `>`, `>=` and `sort()`
+    pub collation: Collation,
+    /// Replace a missing property or null host result used in arithmetic with a literal by the
+    /// zero value of the literal's type, so `device.missingCount + 5` is `5` instead of failing
+    #[serde(alias = "arithmeticDefaults")]
+    pub arithmetic_defaults: bool,
+    /// The result a host answers with for a property that doesn't exist, e.g. `__ABSENT__`, as
+    /// opposed to `null` for one that exists without a value. Absent properties read as `null`
+    /// but fail `has()`
+    #[serde(alias = "missingProperty")]
+    pub missing_property: Option<String>,
+    /// Issue the host calls whose arguments are all literals at once before evaluating, instead
+    /// of one after the other as the evaluation reaches them
+    #[serde(alias = "concurrentHostCalls")]
+    pub concurrent_host_calls: bool,
+    /// Evaluate without calling the host, returning a `RequiresHost` outcome naming the first
+    /// host property the evaluation reaches
+    #[serde(alias = "noHostCalls")]
+    pub no_host_calls: bool,
+    /// Return results in a canonical form, so logically equal results serialize identically:
+    /// floats holding an integer and uints become ints, and map keys are sorted
+    pub canonicalize: bool,
+}
+
+/// Outcome of an evaluation whose `requires` declaration is not met by the host
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CapabilityFallback {
+    /// Fail with an `UnsupportedHostCapability` error
+    #[default]
+    Error,
+    /// Evaluate to `true`
+    FailOpen,
+    /// Evaluate to `false`
+    FailClosed,
+}
+
+/// Order of strings in comparisons and sorting. Equality (`==`, `!=`) is never affected.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Collation {
+    /// Compare the bytes of the strings, so `"Banana" < "apple"`
+    #[default]
+    Binary,
+    /// Compare the strings ignoring case and the accents of Latin letters, so `"apple" < "Banana"`
+    /// and `"é" < "f"`. Strings that only differ by case or accents are ordered bytewise
+    CaseInsensitive,
+}
+
+/// Division of two integer operands. Divisions involving a float always give a float.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegerDivision {
+    /// Truncate towards zero as CEL specifies, so `5 / 2` is `2`
+    #[default]
+    Truncate,
+    /// Promote the operands to floats, so `5 / 2` is `2.5`
+    Float,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct PassableMap {
     pub map: HashMap<String, PassableValue>,
 }
@@ -23,6 +296,317 @@ impl PassableMap {
     pub fn new(map: HashMap<String, PassableValue>) -> Self {
         Self { map }
     }
+
+    /// Exposes the results of previously evaluated rules as the `results` namespace, so
+    /// expressions can reference them as `results.<ruleId>`. Prior results take precedence
+    /// over a `results` variable; without any, an existing `results` variable is kept.
+    pub fn with_prior_results(mut self, prior_results: HashMap<String, PassableValue>) -> Self {
+        if !prior_results.is_empty() || !self.map.contains_key(PRIOR_RESULTS_NAMESPACE) {
+            self.map.insert(
+                PRIOR_RESULTS_NAMESPACE.to_string(),
+                PassableValue::PMap(prior_results),
+            );
+        }
+        self
+    }
+
+    /// Deep-merges the overrides onto these variables: nested maps are merged key by key,
+    /// while any other value, lists included, is replaced by the override.
+    pub fn merged_with(mut self, overrides: PassableMap) -> Self {
+        for (key, value) in overrides.map {
+            let merged = match self.map.remove(&key) {
+                Some(base) => merge_values(base, value),
+                None => value,
+            };
+            self.map.insert(key, merged);
+        }
+        self
+    }
+
+    /// Sets the value at a dotted path like `user.profile.tier`, creating the missing maps along
+    /// it. Fails with the part of the path holding a value other than a map, leaving the
+    /// variables unchanged.
+    pub(crate) fn insert_at(&mut self, path: &str, value: PassableValue) -> Result<(), String> {
+        let mut segments: Vec<&str> = path.split('.').collect();
+        let last = segments.pop().unwrap_or_default();
+        let mut map = &mut self.map;
+        for (index, segment) in segments.iter().enumerate() {
+            let entry = map
+                .entry(segment.to_string())
+                .or_insert_with(|| PassableValue::PMap(HashMap::new()));
+            map = match entry {
+                PassableValue::PMap(nested) => nested,
+                _ => return Err(segments[..=index].join(".")),
+            };
+        }
+        map.insert(last.to_string(), value);
+        Ok(())
+    }
+}
+
+fn merge_values(base: PassableValue, overrides: PassableValue) -> PassableValue {
+    match (base, overrides) {
+        (PassableValue::PMap(base), PassableValue::PMap(overrides)) => PassableValue::PMap(
+            PassableMap::new(base)
+                .merged_with(PassableMap::new(overrides))
+                .map,
+        ),
+        (_, overrides) => overrides,
+    }
+}
+
+/// A step from a value to one nested in it: an index into a list or a key of a map
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub(crate) enum ValuePathSegment {
+    Index(usize),
+    Key(String),
+}
+
+/// The key order of an expression's map literals, each keyed by the path its map takes in the
+/// result, e.g. `["a"]` for the inner map of `{"b": 1, "a": {"a": 1, "b": 2}}`. The default has no
+/// literals, so every map is sorted by key.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct MapKeyOrder {
+    /// The key order of the literals that can end up at each path, in source order
+    pub(crate) by_path: HashMap<Vec<ValuePathSegment>, Vec<IndexSet<String>>>,
+    /// The key order of every literal, in source order
+    pub(crate) literals: Vec<IndexSet<String>>,
+}
+
+impl MapKeyOrder {
+    /// The key order of the map at `path`: that of a literal at the same path, preferring one
+    /// with the same keys, else that of a literal elsewhere with the same keys, like the body of
+    /// a `map` macro, else the order of the keys across every literal
+    fn of(&self, path: &[ValuePathSegment], keys: &[&String]) -> IndexSet<String> {
+        let same_keys = |order: &&IndexSet<String>| {
+            order.len() == keys.len() && keys.iter().all(|key| order.contains(key.as_str()))
+        };
+        let at_path = self
+            .by_path
+            .get(path)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        at_path
+            .iter()
+            .find(same_keys)
+            .or_else(|| at_path.first())
+            .or_else(|| self.literals.iter().find(same_keys))
+            .cloned()
+            .unwrap_or_else(|| self.literals.iter().flatten().cloned().collect())
+    }
+}
+
+/// Serializes a value like `PassableValue`, but with the keys of each map ordered by the map
+/// literal it comes from in `key_order`. Keys missing from the literal come last, sorted, so the
+/// output is deterministic.
+pub(crate) struct OrderedValue<'a> {
+    pub(crate) value: &'a PassableValue,
+    pub(crate) key_order: &'a MapKeyOrder,
+    pub(crate) path: Vec<ValuePathSegment>,
+}
+
+impl OrderedValue<'_> {
+    fn nested<'a>(
+        &'a self,
+        value: &'a PassableValue,
+        segment: ValuePathSegment,
+    ) -> OrderedValue<'a> {
+        let mut path = self.path.clone();
+        path.push(segment);
+        OrderedValue {
+            value,
+            key_order: self.key_order,
+            path,
+        }
+    }
+}
+
+impl Serialize for OrderedValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state;
+        match self.value {
+            PassableValue::PMap(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                let key_order = self.key_order.of(&self.path, &keys);
+                keys.sort_by_key(|key| {
+                    (
+                        key_order.get_index_of(key.as_str()).unwrap_or(usize::MAX),
+                        key.as_str(),
+                    )
+                });
+                let entries: IndexMap<&String, OrderedValue> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let segment = ValuePathSegment::Key(key.clone());
+                        (key, self.nested(&map[key], segment))
+                    })
+                    .collect();
+                state = serializer.serialize_struct("PassableValue", 2)?;
+                state.serialize_field("type", "map")?;
+                state.serialize_field("value", &entries)?;
+            }
+            PassableValue::List(list) => {
+                let items: Vec<OrderedValue> = list
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| self.nested(item, ValuePathSegment::Index(index)))
+                    .collect();
+                state = serializer.serialize_struct("PassableValue", 2)?;
+                state.serialize_field("type", "list")?;
+                state.serialize_field("value", &items)?;
+            }
+            other => return other.serialize(serializer),
+        }
+        state.end()
+    }
+}
+
+/// The variable prior rule results are exposed under
+const PRIOR_RESULTS_NAMESPACE: &str = "results";
+
+/// Result of evaluating an expression together with the value of each of its leaf conditions,
+/// in source order, and the branches the evaluation skipped
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ConditionResults {
+    pub result: PassableValue,
+    pub conditions: Vec<ConditionValue>,
+    /// The sources of the ternary branches and right operands of `&&`/`||` that weren't
+    /// evaluated, in source order
+    pub skipped_branches: Vec<String>,
+}
+
+/// A leaf condition by its source, with its value, or `None` if the evaluation short-circuited
+/// it. Conditions repeated in the expression are listed once per occurrence
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ConditionValue {
+    pub condition: String,
+    pub value: Option<PassableValue>,
+}
+
+/// A variable or host property result before and after normalization. Host results carry the
+/// arguments of the call that produced them
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NormalizedValue {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<PassableValue>>,
+    pub raw: PassableValue,
+    pub normalized: PassableValue,
+}
+
+/// Result of evaluating an expression together with the normalization applied to each
+/// referenced variable and host property result
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NormalizationTrace {
+    pub result: PassableValue,
+    pub values: Vec<NormalizedValue>,
+}
+
+/// Result of evaluating an expression together with the host results it was computed from,
+/// keyed by qualified function name and canonical arguments, to replay with the `replay` option
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HostSnapshot {
+    pub result: PassableValue,
+    pub snapshot: BTreeMap<String, PassableValue>,
+}
+
+/// Result of evaluating an expression together with the warnings about it and its bindings,
+/// like calls to deprecated functions
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EvaluationWithWarnings {
+    pub result: PassableValue,
+    pub warnings: Vec<String>,
+}
+
+/// Estimated worst-case cost of evaluating an expression, from `estimate_cost`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct CostEstimate {
+    /// The weighted total: 100 per host call, 5 per function call and 1 per operator, field
+    /// read, index and element visited
+    pub cost: u64,
+    /// Calls to `device`/`computed` functions, including reads of host namespaces
+    pub host_calls: u64,
+    /// Calls to built-in functions and methods, like `size` or `contains`
+    pub function_calls: u64,
+    pub arithmetic_ops: u64,
+    pub comparisons: u64,
+    /// Elements visited by comprehensions like `all(x, ...)` and by `in`
+    pub iterations: u64,
+    /// The number of elements of the largest collection the expression builds or iterates over
+    pub largest_collection: u64,
+}
+
+impl CostEstimate {
+    pub(crate) fn add(&mut self, other: CostEstimate) {
+        self.cost = self.cost.saturating_add(other.cost);
+        self.host_calls = self.host_calls.saturating_add(other.host_calls);
+        self.function_calls = self.function_calls.saturating_add(other.function_calls);
+        self.arithmetic_ops = self.arithmetic_ops.saturating_add(other.arithmetic_ops);
+        self.comparisons = self.comparisons.saturating_add(other.comparisons);
+        self.iterations = self.iterations.saturating_add(other.iterations);
+        self.largest_collection = self.largest_collection.max(other.largest_collection);
+    }
+
+    /// The estimate of doing this `times` times over
+    pub(crate) fn scaled(self, times: u64) -> CostEstimate {
+        CostEstimate {
+            cost: self.cost.saturating_mul(times),
+            host_calls: self.host_calls.saturating_mul(times),
+            function_calls: self.function_calls.saturating_mul(times),
+            arithmetic_ops: self.arithmetic_ops.saturating_mul(times),
+            comparisons: self.comparisons.saturating_mul(times),
+            iterations: self.iterations.saturating_mul(times),
+            largest_collection: self.largest_collection,
+        }
+    }
+}
+
+/// A case of `evaluate_batch_for_golden`: an expression, the context to evaluate it in and the
+/// result it is expected to produce, as returned by `evaluate_with_context`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct GoldenCase {
+    pub(crate) name: Option<String>,
+    pub(crate) expression: String,
+    #[serde(default)]
+    pub(crate) context: ContextLayer,
+    #[serde(alias = "expectedResult")]
+    pub(crate) expected_result: Result<PassableValue, String>,
+}
+
+/// The outcome of a golden case, with a line per difference between its expected and actual
+/// results
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GoldenCaseResult {
+    pub name: String,
+    pub passed: bool,
+    pub expected: Result<PassableValue, String>,
+    pub actual: Result<PassableValue, String>,
+    pub differences: Vec<String>,
+}
+
+/// The outcomes of a batch of golden cases, in the order of the cases
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GoldenReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub cases: Vec<GoldenCaseResult>,
+}
+
+/// A value an expression read, with where it came from: a `literal` of the expression, a
+/// `variable`, or a `device` or `computed` property
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ProvenanceValue {
+    pub kind: String,
+    /// The source of the value in the expression, e.g. `user.plan` or `device.daysSince("install")`
+    pub origin: String,
+    pub value: PassableValue,
+}
+
+/// Result of evaluating an expression together with the provenance of every value it read
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ProvenanceTrace {
+    pub result: PassableValue,
+    pub values: Vec<ProvenanceValue>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,6 +635,148 @@ pub enum PassableValue {
     Null,
 }
 
+/// A value returned by the host through `ResultCallback::on_value`, or by the typed evaluation
+/// entry points, the typed counterpart of a JSON serialized `PassableValue` without function
+/// values, which hosts never return
+#[derive(Debug, PartialEq, Clone)]
+pub enum HostValue {
+    List { values: Vec<HostValue> },
+    Map { entries: HashMap<String, HostValue> },
+    Int { value: i64 },
+    UInt { value: u64 },
+    Float { value: f64 },
+    String { value: String },
+    Bytes { value: Vec<u8> },
+    Bool { value: bool },
+    Timestamp { value: i64 },
+    Null,
+}
+
+impl From<HostValue> for PassableValue {
+    fn from(value: HostValue) -> Self {
+        match value {
+            HostValue::List { values } => {
+                PassableValue::List(values.into_iter().map(PassableValue::from).collect())
+            }
+            HostValue::Map { entries } => PassableValue::PMap(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, PassableValue::from(value)))
+                    .collect(),
+            ),
+            HostValue::Int { value } => PassableValue::Int(value),
+            HostValue::UInt { value } => PassableValue::UInt(value),
+            HostValue::Float { value } => PassableValue::Float(value),
+            HostValue::String { value } => PassableValue::String(value),
+            HostValue::Bytes { value } => PassableValue::Bytes(value),
+            HostValue::Bool { value } => PassableValue::Bool(value),
+            HostValue::Timestamp { value } => PassableValue::Timestamp(value),
+            HostValue::Null => PassableValue::Null,
+        }
+    }
+}
+
+/// Function values, which only a reference to a host function evaluates to, become `Null`
+impl From<PassableValue> for HostValue {
+    fn from(value: PassableValue) -> Self {
+        match value {
+            PassableValue::List(values) => HostValue::List {
+                values: values.into_iter().map(HostValue::from).collect(),
+            },
+            PassableValue::PMap(entries) => HostValue::Map {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| (key, HostValue::from(value)))
+                    .collect(),
+            },
+            PassableValue::Function(_, _) | PassableValue::Null => HostValue::Null,
+            PassableValue::Int(value) => HostValue::Int { value },
+            PassableValue::UInt(value) => HostValue::UInt { value },
+            PassableValue::Float(value) => HostValue::Float { value },
+            PassableValue::String(value) => HostValue::String { value },
+            PassableValue::Bytes(value) => HostValue::Bytes { value },
+            PassableValue::Bool(value) => HostValue::Bool { value },
+            PassableValue::Timestamp(value) => HostValue::Timestamp { value },
+        }
+    }
+}
+
+impl PassableValue {
+    /// The type name used as the serialized `type` tag of this value
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PassableValue::List(_) => "list",
+            PassableValue::PMap(_) => "map",
+            PassableValue::Function(_, _) => "function",
+            PassableValue::Int(_) => "int",
+            PassableValue::UInt(_) => "uint",
+            PassableValue::Float(_) => "float",
+            PassableValue::String(_) => "string",
+            PassableValue::Bytes(_) => "bytes",
+            PassableValue::Bool(_) => "bool",
+            PassableValue::Timestamp(_) => "timestamp",
+            PassableValue::Null => "null",
+        }
+    }
+
+    /// The canonical form of this value, for the `canonicalize` option: floats holding an
+    /// integer become ints, as do uints within the range of ints, nested values included, so
+    /// `1`, `uint(1)` and `1.0` are all `1`
+    pub(crate) fn canonicalized(self) -> PassableValue {
+        match self {
+            PassableValue::List(list) => {
+                PassableValue::List(list.into_iter().map(PassableValue::canonicalized).collect())
+            }
+            PassableValue::PMap(map) => PassableValue::PMap(
+                map.into_iter()
+                    .map(|(key, value)| (key, value.canonicalized()))
+                    .collect(),
+            ),
+            // 2^63 is exact as a float, and the first float past the range of ints
+            PassableValue::Float(f)
+                if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 =>
+            {
+                PassableValue::Int(f as i64)
+            }
+            PassableValue::UInt(u) => match i64::try_from(u) {
+                Ok(i) => PassableValue::Int(i),
+                Err(_) => PassableValue::UInt(u),
+            },
+            value => value,
+        }
+    }
+}
+
+impl From<serde_json::Value> for PassableValue {
+    /// Converts plain (untagged) JSON into a `PassableValue`, preferring `int`, then `uint`,
+    /// then `float` for numbers
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => PassableValue::Null,
+            serde_json::Value::Bool(b) => PassableValue::Bool(b),
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    PassableValue::Int(i)
+                } else if let Some(u) = n.as_u64() {
+                    PassableValue::UInt(u)
+                } else {
+                    PassableValue::Float(n.as_f64().unwrap_or(f64::NAN))
+                }
+            }
+            serde_json::Value::String(s) => PassableValue::String(s),
+            serde_json::Value::Array(items) => {
+                PassableValue::List(items.into_iter().map(PassableValue::from).collect())
+            }
+            serde_json::Value::Object(entries) => PassableValue::PMap(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, PassableValue::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl PartialEq for PassableValue {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -73,20 +799,27 @@ impl PartialEq for PassableValue {
                 .try_into()
                 .map(|a: u64| a == *b)
                 .unwrap_or(false),
-            (PassableValue::Int(a), PassableValue::Float(b)) => (*a as f64) == *b,
+            (PassableValue::Int(a), PassableValue::Float(b)) => integer_eq_float(*a as i128, *b),
             (PassableValue::UInt(a), PassableValue::Int(b)) => a
                 .to_owned()
                 .try_into()
                 .map(|a: i64| a == *b)
                 .unwrap_or(false),
-            (PassableValue::UInt(a), PassableValue::Float(b)) => (*a as f64) == *b,
-            (PassableValue::Float(a), PassableValue::Int(b)) => *a == (*b as f64),
-            (PassableValue::Float(a), PassableValue::UInt(b)) => *a == (*b as f64),
+            (PassableValue::UInt(a), PassableValue::Float(b)) => integer_eq_float(*a as i128, *b),
+            (PassableValue::Float(a), PassableValue::Int(b)) => integer_eq_float(*b as i128, *a),
+            (PassableValue::Float(a), PassableValue::UInt(b)) => integer_eq_float(*b as i128, *a),
             (_, _) => false,
         }
     }
 }
 
+/// Compares an integer with a float without rounding the integer through `f64`, so integers
+/// beyond 2^53 only equal a float that represents them exactly
+fn integer_eq_float(integer: i128, float: f64) -> bool {
+    // `as` saturates, and no i64/u64 value reaches the i128 bounds
+    float.is_finite() && float.fract() == 0.0 && float as i128 == integer
+}
+
 impl fmt::Display for PassableValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -0,0 +1,533 @@
+//! Protobuf messages for `evaluate_with_context_proto`, mirroring `proto/superscript.proto`.
+//! They are declared by hand with `prost` derives so building doesn't require `protoc`.
+use crate::models::{
+    self, CapabilityFallback as Fallback, Collation as Order, EvaluationOptions as Options,
+    IntegerDivision as Division,
+};
+use prost::Message;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PassableValue {
+    #[prost(
+        oneof = "passable_value::Kind",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11"
+    )]
+    pub kind: Option<passable_value::Kind>,
+}
+
+pub mod passable_value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        List(super::PassableList),
+        #[prost(message, tag = "2")]
+        Map(super::PassableMap),
+        #[prost(message, tag = "3")]
+        Function(super::FunctionValue),
+        #[prost(int64, tag = "4")]
+        Int(i64),
+        #[prost(uint64, tag = "5")]
+        Uint(u64),
+        #[prost(double, tag = "6")]
+        Float(f64),
+        #[prost(string, tag = "7")]
+        String(String),
+        #[prost(bytes = "vec", tag = "8")]
+        Bytes(Vec<u8>),
+        #[prost(bool, tag = "9")]
+        Bool(bool),
+        #[prost(int64, tag = "10")]
+        Timestamp(i64),
+        #[prost(message, tag = "11")]
+        Null(super::Null),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Null {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PassableList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<PassableValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PassableMap {
+    #[prost(map = "string, message", tag = "1")]
+    pub entries: HashMap<String, PassableValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FunctionValue {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub args: Option<Box<PassableValue>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum CapabilityFallback {
+    Error = 0,
+    FailOpen = 1,
+    FailClosed = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum IntegerDivision {
+    Truncate = 0,
+    Float = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Collation {
+    Binary = 0,
+    CaseInsensitive = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EvaluationOptions {
+    #[prost(map = "string, string", tag = "1")]
+    pub return_types: HashMap<String, String>,
+    #[prost(bool, tag = "2")]
+    pub strict_return_types: bool,
+    #[prost(string, repeated, tag = "3")]
+    pub requires: Vec<String>,
+    #[prost(enumeration = "CapabilityFallback", tag = "4")]
+    pub unsupported_capability: i32,
+    #[prost(bool, tag = "5")]
+    pub trim_to_null: bool,
+    #[prost(uint64, optional, tag = "6")]
+    pub max_host_calls: Option<u64>,
+    #[prost(bool, tag = "7")]
+    pub strict_deprecations: bool,
+    #[prost(bool, tag = "8")]
+    pub ordered_maps: bool,
+    #[prost(enumeration = "IntegerDivision", tag = "9")]
+    pub integer_division: i32,
+    #[prost(int64, optional, tag = "10")]
+    pub now: Option<i64>,
+    #[prost(bool, tag = "11")]
+    pub fold_constants: bool,
+    #[prost(uint64, optional, tag = "12")]
+    pub result_cache_ttl_ms: Option<u64>,
+    #[prost(string, repeated, tag = "13")]
+    pub expensive: Vec<String>,
+    #[prost(string, optional, tag = "14")]
+    pub locale: Option<String>,
+    #[prost(map = "string, message", tag = "15")]
+    pub replay: HashMap<String, PassableValue>,
+    #[prost(enumeration = "Collation", tag = "16")]
+    pub collation: i32,
+    #[prost(bool, tag = "17")]
+    pub arithmetic_defaults: bool,
+    #[prost(string, optional, tag = "18")]
+    pub missing_property: Option<String>,
+    #[prost(bool, tag = "19")]
+    pub concurrent_host_calls: bool,
+    #[prost(bool, tag = "20")]
+    pub no_host_calls: bool,
+    #[prost(uint64, optional, tag = "21")]
+    pub max_string_length: Option<u64>,
+    #[prost(bool, tag = "22")]
+    pub canonicalize: bool,
+    #[prost(map = "string, message", tag = "23")]
+    pub default_results: HashMap<String, PassableValue>,
+    #[prost(bool, tag = "24")]
+    pub strict_variable_types: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExecutionContext {
+    #[prost(message, optional, tag = "1")]
+    pub variables: Option<PassableMap>,
+    #[prost(string, tag = "2")]
+    pub expression: String,
+    #[prost(map = "string, message", tag = "3")]
+    pub computed: HashMap<String, PassableList>,
+    #[prost(map = "string, message", tag = "4")]
+    pub device: HashMap<String, PassableList>,
+    #[prost(message, optional, tag = "5")]
+    pub options: Option<EvaluationOptions>,
+    #[prost(map = "string, message", tag = "6")]
+    pub prior_results: HashMap<String, PassableValue>,
+    #[prost(map = "string, string", tag = "7")]
+    pub bindings: HashMap<String, String>,
+    #[prost(string, repeated, tag = "8")]
+    pub namespaces: Vec<String>,
+    #[prost(map = "string, message", tag = "9")]
+    pub custom_types: HashMap<String, CustomType>,
+    #[prost(map = "string, string", tag = "10")]
+    pub variable_types: HashMap<String, String>,
+    #[prost(map = "string, string", tag = "11")]
+    pub inline_functions: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CustomType {
+    #[prost(string, tag = "1")]
+    pub compare: String,
+    #[prost(string, repeated, tag = "2")]
+    pub values: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EvaluationResult {
+    #[prost(oneof = "evaluation_result::Result", tags = "1, 2")]
+    pub result: Option<evaluation_result::Result>,
+}
+
+pub mod evaluation_result {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Result {
+        #[prost(message, tag = "1")]
+        Ok(super::PassableValue),
+        #[prost(string, tag = "2")]
+        Err(String),
+    }
+}
+
+impl From<PassableValue> for models::PassableValue {
+    fn from(value: PassableValue) -> Self {
+        use passable_value::Kind;
+        match value.kind {
+            Some(Kind::List(list)) => models::PassableValue::List(
+                list.values
+                    .into_iter()
+                    .map(models::PassableValue::from)
+                    .collect(),
+            ),
+            Some(Kind::Map(map)) => models::PassableValue::PMap(map_from_proto(map.entries)),
+            Some(Kind::Function(function)) => models::PassableValue::Function(
+                function.name,
+                function.args.map(|args| Box::new((*args).into())),
+            ),
+            Some(Kind::Int(i)) => models::PassableValue::Int(i),
+            Some(Kind::Uint(u)) => models::PassableValue::UInt(u),
+            Some(Kind::Float(f)) => models::PassableValue::Float(f),
+            Some(Kind::String(s)) => models::PassableValue::String(s),
+            Some(Kind::Bytes(bytes)) => models::PassableValue::Bytes(bytes),
+            Some(Kind::Bool(b)) => models::PassableValue::Bool(b),
+            Some(Kind::Timestamp(t)) => models::PassableValue::Timestamp(t),
+            Some(Kind::Null(_)) | None => models::PassableValue::Null,
+        }
+    }
+}
+
+impl From<models::PassableValue> for PassableValue {
+    fn from(value: models::PassableValue) -> Self {
+        use passable_value::Kind;
+        let kind = match value {
+            models::PassableValue::List(list) => Kind::List(PassableList {
+                values: list.into_iter().map(PassableValue::from).collect(),
+            }),
+            models::PassableValue::PMap(map) => Kind::Map(PassableMap {
+                entries: map.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            }),
+            models::PassableValue::Function(name, args) => Kind::Function(FunctionValue {
+                name,
+                args: args.map(|args| Box::new((*args).into())),
+            }),
+            models::PassableValue::Int(i) => Kind::Int(i),
+            models::PassableValue::UInt(u) => Kind::Uint(u),
+            models::PassableValue::Float(f) => Kind::Float(f),
+            models::PassableValue::String(s) => Kind::String(s),
+            models::PassableValue::Bytes(bytes) => Kind::Bytes(bytes),
+            models::PassableValue::Bool(b) => Kind::Bool(b),
+            models::PassableValue::Timestamp(t) => Kind::Timestamp(t),
+            models::PassableValue::Null => Kind::Null(Null {}),
+        };
+        PassableValue { kind: Some(kind) }
+    }
+}
+
+impl From<EvaluationOptions> for Options {
+    fn from(options: EvaluationOptions) -> Self {
+        let unsupported_capability = match options.unsupported_capability() {
+            CapabilityFallback::Error => Fallback::Error,
+            CapabilityFallback::FailOpen => Fallback::FailOpen,
+            CapabilityFallback::FailClosed => Fallback::FailClosed,
+        };
+        let integer_division = match options.integer_division() {
+            IntegerDivision::Truncate => Division::Truncate,
+            IntegerDivision::Float => Division::Float,
+        };
+        let collation = match options.collation() {
+            Collation::Binary => Order::Binary,
+            Collation::CaseInsensitive => Order::CaseInsensitive,
+        };
+        Options {
+            return_types: options.return_types,
+            strict_return_types: options.strict_return_types,
+            requires: options.requires,
+            unsupported_capability,
+            trim_to_null: options.trim_to_null,
+            max_host_calls: options.max_host_calls.map(|max| max as usize),
+            strict_deprecations: options.strict_deprecations,
+            ordered_maps: options.ordered_maps,
+            integer_division,
+            now: options.now,
+            fold_constants: options.fold_constants,
+            result_cache_ttl_ms: options.result_cache_ttl_ms,
+            expensive: options.expensive,
+            locale: options.locale,
+            // An empty snapshot is the same as not replaying one
+            replay: (!options.replay.is_empty()).then(|| map_from_proto(options.replay)),
+            collation,
+            arithmetic_defaults: options.arithmetic_defaults,
+            missing_property: options.missing_property,
+            concurrent_host_calls: options.concurrent_host_calls,
+            no_host_calls: options.no_host_calls,
+            max_string_length: options
+                .max_string_length
+                .map(|max| usize::try_from(max).unwrap_or(usize::MAX)),
+            canonicalize: options.canonicalize,
+            default_results: map_from_proto(options.default_results),
+            strict_variable_types: options.strict_variable_types,
+        }
+    }
+}
+
+fn map_from_proto(
+    entries: HashMap<String, PassableValue>,
+) -> HashMap<String, models::PassableValue> {
+    entries.into_iter().map(|(k, v)| (k, v.into())).collect()
+}
+
+// An empty map of host functions is the same as not declaring any
+fn functions_from_proto(
+    functions: HashMap<String, PassableList>,
+) -> Option<HashMap<String, Vec<models::PassableValue>>> {
+    if functions.is_empty() {
+        return None;
+    }
+    Some(
+        functions
+            .into_iter()
+            .map(|(name, args)| (name, args.values.into_iter().map(Into::into).collect()))
+            .collect(),
+    )
+}
+
+/**
+ * Decodes a protobuf `ExecutionContext` into the context used by the JSON path.
+ */
+pub(crate) fn decode_execution_context(
+    bytes: &[u8],
+) -> Result<models::ExecutionContext, prost::DecodeError> {
+    let context = ExecutionContext::decode(bytes)?;
+    Ok(models::ExecutionContext {
+        variables: models::PassableMap::new(map_from_proto(
+            context.variables.unwrap_or_default().entries,
+        )),
+        expression: context.expression,
+        computed: functions_from_proto(context.computed),
+        device: functions_from_proto(context.device),
+        options: context.options.map(Into::into).unwrap_or_default(),
+        prior_results: map_from_proto(context.prior_results),
+        bindings: context.bindings,
+        namespaces: context.namespaces,
+        custom_types: context
+            .custom_types
+            .into_iter()
+            .map(|(name, custom_type)| {
+                let custom_type = models::CustomType {
+                    compare: custom_type.compare,
+                    values: custom_type.values,
+                };
+                (name, custom_type)
+            })
+            .collect(),
+        variable_types: context.variable_types,
+        inline_functions: context.inline_functions,
+    })
+}
+
+/**
+ * Encodes the result of an evaluation as a protobuf `EvaluationResult`.
+ */
+pub(crate) fn encode_result(result: Result<models::PassableValue, String>) -> Vec<u8> {
+    let result = match result {
+        Ok(value) => evaluation_result::Result::Ok(value.into()),
+        Err(err) => evaluation_result::Result::Err(err),
+    };
+    EvaluationResult {
+        result: Some(result),
+    }
+    .encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{evaluate_with_context, evaluate_with_context_proto, HostContext, ResultCallback};
+    use std::sync::Arc;
+
+    struct ProtoTestContext;
+
+    impl HostContext for ProtoTestContext {
+        fn computed_property(&self, name: String, args: String, callback: Arc<dyn ResultCallback>) {
+            self.device_property(name, args, callback)
+        }
+
+        fn device_property(&self, name: String, _args: String, callback: Arc<dyn ResultCallback>) {
+            let value = match name.as_str() {
+                "daysSince" => models::PassableValue::Int(4),
+                _ => models::PassableValue::String("premium".to_string()),
+            };
+            callback.on_result(serde_json::to_string(&value).unwrap());
+        }
+    }
+
+    fn proto_value(value: models::PassableValue) -> PassableValue {
+        value.into()
+    }
+
+    #[test]
+    fn test_proto_and_json_evaluate_identically() {
+        let variables: HashMap<String, models::PassableValue> = HashMap::from([
+            (
+                "user".to_string(),
+                models::PassableValue::PMap(HashMap::from([
+                    ("credits".to_string(), models::PassableValue::Int(12)),
+                    (
+                        "tags".to_string(),
+                        models::PassableValue::List(vec![models::PassableValue::String(
+                            "beta".to_string(),
+                        )]),
+                    ),
+                    ("nickname".to_string(), models::PassableValue::Null),
+                ])),
+            ),
+            ("ratio".to_string(), models::PassableValue::Float(0.5)),
+            ("big".to_string(), models::PassableValue::UInt(u64::MAX)),
+        ]);
+        let device = HashMap::from([(
+            "daysSince".to_string(),
+            vec![models::PassableValue::String("app_install".to_string())],
+        )]);
+        let computed = HashMap::from([("tier".to_string(), vec![])]);
+
+        for expression in [
+            "user.credits > 10 && 'beta' in user.tags",
+            "device.daysSince() > 3 && computed.tier() == 'premium'",
+            "user.nickname == null && ratio * 2.0 == 1.0",
+            "big > uint(1)",
+            "user.missing.deeper",
+            "results.previous == true",
+            "user.credits + 'a'",
+            "user.credits >",
+        ] {
+            let json = evaluate_with_context(
+                serde_json::json!({
+                    "variables": {"map": variables},
+                    "expression": expression,
+                    "device": device,
+                    "computed": computed,
+                    "options": {"max_host_calls": 5},
+                    "prior_results": {"previous": models::PassableValue::Bool(true)},
+                })
+                .to_string(),
+                Arc::new(ProtoTestContext),
+            );
+
+            let context = ExecutionContext {
+                variables: Some(PassableMap {
+                    entries: variables
+                        .iter()
+                        .map(|(k, v)| (k.clone(), proto_value(v.clone())))
+                        .collect(),
+                }),
+                expression: expression.to_string(),
+                computed: computed
+                    .iter()
+                    .map(|(k, v)| {
+                        let values = v.iter().cloned().map(proto_value).collect();
+                        (k.clone(), PassableList { values })
+                    })
+                    .collect(),
+                device: device
+                    .iter()
+                    .map(|(k, v)| {
+                        let values = v.iter().cloned().map(proto_value).collect();
+                        (k.clone(), PassableList { values })
+                    })
+                    .collect(),
+                options: Some(EvaluationOptions {
+                    max_host_calls: Some(5),
+                    ..Default::default()
+                }),
+                prior_results: HashMap::from([(
+                    "previous".to_string(),
+                    proto_value(models::PassableValue::Bool(true)),
+                )]),
+                bindings: HashMap::new(),
+                namespaces: Vec::new(),
+                custom_types: HashMap::new(),
+                variable_types: HashMap::new(),
+                inline_functions: HashMap::new(),
+            };
+            let encoded =
+                evaluate_with_context_proto(context.encode_to_vec(), Arc::new(ProtoTestContext));
+            let proto = match EvaluationResult::decode(encoded.as_slice()).unwrap().result {
+                Some(evaluation_result::Result::Ok(value)) => {
+                    Ok(models::PassableValue::from(value))
+                }
+                Some(evaluation_result::Result::Err(err)) => Err(err),
+                None => panic!("Missing result for {}", expression),
+            };
+
+            assert_eq!(
+                serde_json::to_string(&proto).unwrap(),
+                json,
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_proto_values_round_trip() {
+        let value = models::PassableValue::List(vec![
+            models::PassableValue::Int(-1),
+            models::PassableValue::UInt(u64::MAX),
+            models::PassableValue::Float(1.5),
+            models::PassableValue::String("a".to_string()),
+            models::PassableValue::Bytes(vec![0, 255]),
+            models::PassableValue::Bool(true),
+            models::PassableValue::Timestamp(1_700_000_000),
+            models::PassableValue::Null,
+            models::PassableValue::Function(
+                "daysSince".to_string(),
+                Some(Box::new(models::PassableValue::String("x".to_string()))),
+            ),
+            models::PassableValue::PMap(HashMap::from([(
+                "k".to_string(),
+                models::PassableValue::Int(1),
+            )])),
+        ]);
+        let encoded = proto_value(value.clone()).encode_to_vec();
+        let decoded: models::PassableValue =
+            PassableValue::decode(encoded.as_slice()).unwrap().into();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_invalid_proto_is_an_error() {
+        let encoded = evaluate_with_context_proto(vec![0xff, 0xff], Arc::new(ProtoTestContext));
+        match EvaluationResult::decode(encoded.as_slice()).unwrap().result {
+            Some(evaluation_result::Result::Err(err)) => {
+                assert!(
+                    err.starts_with("Invalid execution context protobuf"),
+                    "{}",
+                    err
+                )
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+}
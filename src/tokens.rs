@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+
+/**
+ * A token of an expression, as rendered by editors highlighting its syntax.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Token {
+    /// `identifier`, `namespace`, `field`, `function`, `string`, `bytes`, `number`, `bool`,
+    /// `null`, `operator`, `punctuation` or `comment`
+    pub kind: String,
+    pub text: String,
+    /// Character offset of the token's first character
+    pub start: usize,
+    /// Character offset just past the token's last character
+    pub end: usize,
+}
+
+// The host namespaces, highlighted apart from variables
+const NAMESPACES: &[&str] = &["device", "computed"];
+
+// Operators, longest first so `<=` isn't lexed as `<` followed by `=`, as the parser lexes them
+const OPERATORS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "!!", "--", "<", ">", "!", "+", "-", "*", "/", "%", "?",
+];
+
+/**
+ * Splits an expression into tokens with their character spans, as the parser lexes them, and
+ * classifies identifiers by how they are used: `device`/`computed` followed by `.` are
+ * namespaces, identifiers followed by `(` are functions, those following a `.` are fields, and
+ * `in` is an operator. A `:` is the operator of the ternary its bracket opened, and punctuation
+ * in map literals. The expression is expected to parse; whitespace is skipped and unknown
+ * characters become punctuation.
+ */
+pub(crate) fn tokenize(expression: &str) -> Vec<Token> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    // The ternaries awaiting their `:`, per open bracket
+    let mut ternaries = vec![0usize];
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        let start = index;
+        let kind = if c.is_whitespace() {
+            index += 1;
+            continue;
+        } else if c == '/' && chars.get(index + 1) == Some(&'/') {
+            while index < chars.len() && chars[index] != '\n' && chars[index] != '\r' {
+                index += 1;
+            }
+            "comment"
+        } else if let Some(prefix) = string_prefix(&chars[index..]) {
+            let bytes = prefix.iter().any(|p| p.eq_ignore_ascii_case(&'b'));
+            let raw = prefix.iter().any(|p| p.eq_ignore_ascii_case(&'r'));
+            index = string_end(&chars, index + prefix.len(), raw);
+            if bytes {
+                "bytes"
+            } else {
+                "string"
+            }
+        } else if let Some(end) = signed_number_end(&chars, index) {
+            index = end;
+            "number"
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+            {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            let next = chars[index..].iter().find(|c| !c.is_whitespace());
+            let after_dot = tokens
+                .last()
+                .is_some_and(|token| token.text == "." && token.kind == "punctuation");
+            match word.as_str() {
+                "true" | "false" => "bool",
+                "null" => "null",
+                "in" => "operator",
+                _ if next == Some(&'(') => "function",
+                _ if after_dot => "field",
+                name if NAMESPACES.contains(&name) && next == Some(&'.') => "namespace",
+                _ => "identifier",
+            }
+        } else if let Some(operator) = OPERATORS.iter().find(|operator| {
+            operator
+                .chars()
+                .enumerate()
+                .all(|(offset, c)| chars.get(index + offset) == Some(&c))
+        }) {
+            index += operator.chars().count();
+            if *operator == "?" {
+                *ternaries.last_mut().unwrap() += 1;
+            }
+            "operator"
+        } else if c == ':' && ternaries.last().is_some_and(|pending| *pending > 0) {
+            index += 1;
+            *ternaries.last_mut().unwrap() -= 1;
+            "operator"
+        } else {
+            index += 1;
+            match c {
+                '(' | '[' | '{' => ternaries.push(0),
+                ')' | ']' | '}' if ternaries.len() > 1 => {
+                    ternaries.pop();
+                }
+                _ => {}
+            }
+            "punctuation"
+        };
+        tokens.push(Token {
+            kind: kind.to_string(),
+            text: chars[start..index].iter().collect(),
+            start,
+            end: index,
+        });
+    }
+    tokens
+}
+
+// Characters of operators and punctuation, which with ASCII letters, digits, `_` and whitespace are
+// all that can appear outside string literals and comments
+const SYMBOLS: &str = "+-*/%!=<>&|?:.,()[]{}";
+
+/**
+ * The character offset and value of the first character outside string literals and comments
+ * that can't appear in an expression, like a control character or a symbol such as `#` or `@`.
+ * Only ASCII letters, digits, `_`, CEL's whitespace (space, tab, newline, carriage return and
+ * form feed) and the characters of operators and punctuation are allowed there.
+ */
+pub(crate) fn invalid_character(expression: &str) -> Option<(usize, char)> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        if c == '/' && chars.get(index + 1) == Some(&'/') {
+            while index < chars.len() && chars[index] != '\n' && chars[index] != '\r' {
+                index += 1;
+            }
+        } else if let Some(prefix) = string_prefix(&chars[index..]) {
+            let raw = prefix.iter().any(|p| p.eq_ignore_ascii_case(&'r'));
+            index = string_end(&chars, index + prefix.len(), raw);
+        } else if c.is_ascii_alphanumeric()
+            || c == '_'
+            || matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0C')
+            || SYMBOLS.contains(c)
+        {
+            index += 1;
+        } else {
+            return Some((index, c));
+        }
+    }
+    None
+}
+
+// The `r`/`b` prefix characters of the string literal starting here, if one does
+fn string_prefix(chars: &[char]) -> Option<&[char]> {
+    let prefix_len = chars
+        .iter()
+        .take(2)
+        .take_while(|c| matches!(c, 'r' | 'R' | 'b' | 'B'))
+        .count();
+    match chars.get(prefix_len) {
+        Some('\'' | '"') => Some(&chars[..prefix_len]),
+        _ => None,
+    }
+}
+
+// The index just past the string literal whose opening quote is at `index`
+fn string_end(chars: &[char], index: usize, raw: bool) -> usize {
+    let quote = chars[index];
+    let triple = chars.get(index + 1) == Some(&quote) && chars.get(index + 2) == Some(&quote);
+    let delimiter = if triple { 3 } else { 1 };
+    let mut end = index + delimiter;
+    while end < chars.len() {
+        if chars[end] == '\\' && !raw {
+            end += 2;
+        } else if chars[end..]
+            .iter()
+            .take(delimiter)
+            .filter(|c| **c == quote)
+            .count()
+            == delimiter
+        {
+            return end + delimiter;
+        } else {
+            end += 1;
+        }
+    }
+    chars.len()
+}
+
+fn next_is_digit(chars: &[char], index: usize) -> bool {
+    chars.get(index + 1).is_some_and(|c| c.is_ascii_digit())
+}
+
+/**
+ * The index just past the number literal starting at `index`, if one does. Like the parser's
+ * lexer, which matches the longest token, a `-` directly followed by a number is part of it,
+ * and so is a `+` directly followed by a float, so `x > -1` compares with the literal `-1`.
+ */
+fn signed_number_end(chars: &[char], index: usize) -> Option<usize> {
+    let digits = match chars[index] {
+        '-' | '+' => index + 1,
+        _ => index,
+    };
+    let starts_number = chars.get(digits).is_some_and(|c| c.is_ascii_digit())
+        || (chars.get(digits) == Some(&'.') && next_is_digit(chars, digits));
+    if !starts_number {
+        return None;
+    }
+    let end = number_end(chars, digits);
+    let float = chars[digits..end]
+        .iter()
+        .any(|c| matches!(c, '.' | 'e' | 'E'))
+        && !matches!(chars.get(digits + 1), Some('x' | 'X'));
+    match chars[index] {
+        '+' if !float => None,
+        _ => Some(end),
+    }
+}
+
+// The index just past the number literal starting at `index`: decimal or hexadecimal, with an
+// optional fraction, exponent and `u` suffix
+fn number_end(chars: &[char], mut index: usize) -> usize {
+    if chars[index] == '0' && matches!(chars.get(index + 1), Some('x' | 'X')) {
+        index += 2;
+        while index < chars.len() && chars[index].is_ascii_hexdigit() {
+            index += 1;
+        }
+    } else {
+        while index < chars.len() && chars[index].is_ascii_digit() {
+            index += 1;
+        }
+        if chars.get(index) == Some(&'.') && next_is_digit(chars, index) {
+            index += 1;
+            while index < chars.len() && chars[index].is_ascii_digit() {
+                index += 1;
+            }
+        }
+        if matches!(chars.get(index), Some('e' | 'E')) {
+            let sign = usize::from(matches!(chars.get(index + 1), Some('+' | '-')));
+            if chars
+                .get(index + 1 + sign)
+                .is_some_and(|c| c.is_ascii_digit())
+            {
+                index += 1 + sign;
+                while index < chars.len() && chars[index].is_ascii_digit() {
+                    index += 1;
+                }
+            }
+        }
+    }
+    if matches!(chars.get(index), Some('u' | 'U')) {
+        index += 1;
+    }
+    index
+}
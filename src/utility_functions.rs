@@ -1,6 +1,11 @@
-use cel_interpreter::extractors::This;
-use cel_interpreter::{ExecutionError, FunctionContext, Value};
-use cel_parser::Expression;
+use crate::messages::ErrorMessage;
+use crate::models::Collation;
+use crate::DisplayableValue;
+use cel_interpreter::extractors::{Arguments, Identifier, This};
+use cel_interpreter::objects::{Key, Map, ValueType};
+use cel_interpreter::{DateTime, ExecutionError, FixedOffset, FunctionContext, Value};
+use cel_parser::{Expression, Member};
+use std::cmp::Ordering;
 use std::sync::Arc;
 
 /** A method on a string type. When added to the CEL context, this function
@@ -25,6 +30,91 @@ pub fn to_string_b(This(s): This<bool>) -> Arc<String> {
     Arc::new(s.to_string())
 }
 
+/**
+* Converts any value to a string: timestamps in RFC 3339, bytes as their UTF-8 text, and other
+* values as they are displayed, so `null` is `"null"` and maps are JSON objects of their
+* displayed entries.
+*
+* ```cel
+* string(device.build_number) == "1234"
+* ```
+*/
+pub fn to_string(This(this): This<Value>) -> Arc<String> {
+    match this {
+        Value::String(s) => s,
+        Value::Timestamp(t) => Arc::new(t.to_rfc3339()),
+        Value::Bytes(bytes) => Arc::new(String::from_utf8_lossy(&bytes).into_owned()),
+        value => Arc::new(DisplayableValue(value).to_string()),
+    }
+}
+
+/**
+* Converts a string, number or bool to an int, truncating floats toward zero. Strings that don't
+* parse and values out of the range of ints give null, as does null.
+*
+* ```cel
+* int(device.credits) + 5
+* ```
+*/
+pub fn to_int(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let converted = match &this {
+        Value::String(s) => s.trim().parse::<i64>().ok(),
+        Value::Int(i) => Some(*i),
+        Value::UInt(u) => i64::try_from(*u).ok(),
+        // 2^63 is exact as a float, and the first float past the range of ints
+        Value::Float(f) if *f >= i64::MIN as f64 && *f < i64::MAX as f64 => Some(*f as i64),
+        Value::Float(_) => None,
+        Value::Bool(b) => Some(i64::from(*b)),
+        Value::Null => None,
+        _ => return Err(ftx.error(format!("cannot convert {:?} to int", this))),
+    };
+    Ok(converted.map_or(Value::Null, Value::Int))
+}
+
+/**
+* Converts a string, number or bool to a uint, truncating floats toward zero. Strings that don't
+* parse and values out of the range of uints, like negative numbers, give null, as does null.
+*
+* ```cel
+* uint(device.launches) > uint(3)
+* ```
+*/
+pub fn to_uint(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let converted = match &this {
+        Value::String(s) => s.trim().parse::<u64>().ok(),
+        Value::Int(i) => u64::try_from(*i).ok(),
+        Value::UInt(u) => Some(*u),
+        // 2^64 is exact as a float, and the first float past the range of uints
+        Value::Float(f) if *f > -1.0 && *f < u64::MAX as f64 => Some(*f as u64),
+        Value::Float(_) => None,
+        Value::Bool(b) => Some(u64::from(*b)),
+        Value::Null => None,
+        _ => return Err(ftx.error(format!("cannot convert {:?} to uint", this))),
+    };
+    Ok(converted.map_or(Value::Null, Value::UInt))
+}
+
+/**
+* Converts a string, number or bool to a double. Strings that don't parse give null, as does
+* null.
+*
+* ```cel
+* double(device.rating) >= 4.5
+* ```
+*/
+pub fn to_double(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let converted = match &this {
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        Value::Int(i) => Some(*i as f64),
+        Value::UInt(u) => Some(*u as f64),
+        Value::Float(f) => Some(*f),
+        Value::Bool(b) => Some(f64::from(u8::from(*b))),
+        Value::Null => None,
+        _ => return Err(ftx.error(format!("cannot convert {:?} to double", this))),
+    };
+    Ok(converted.map_or(Value::Null, Value::Float))
+}
+
 /**
 * A method that takes in two expressions, and if the left side fails evaluates the right one.
 */
@@ -36,3 +126,844 @@ pub fn maybe(
 ) -> Result<Value, ExecutionError> {
     return ftx.ptx.resolve(&left).or_else(|_| ftx.ptx.resolve(&right));
 }
+
+/**
+* Tests whether a field is present, like CEL's `has` macro: `has(a.b)` is true when `a` is a
+* map holding the key `b`, and false when it doesn't, when `a` is null or not a map, and when
+* `a` is itself missing, even as an undeclared root. Other arguments are present when they
+* resolve.
+*
+* ```cel
+* has(user.profile.name) // false when profile lacks name, or when user has no profile
+* ```
+*/
+pub fn has(ftx: &FunctionContext) -> Result<Value, ExecutionError> {
+    let [arg] = ftx.args.as_slice() else {
+        return Err(ftx.error(format!("has takes 1 argument, got {}", ftx.args.len())));
+    };
+    let Expression::Member(operand, member) = arg else {
+        return resolves(ftx, arg);
+    };
+    let Member::Attribute(name) = member.as_ref() else {
+        return resolves(ftx, arg);
+    };
+    match ftx.ptx.resolve(operand) {
+        Ok(Value::Map(map)) => Ok(Value::Bool(
+            map.map.contains_key(&Key::String(name.clone())),
+        )),
+        Ok(_) => Ok(Value::Bool(false)),
+        Err(err) => missing(err),
+    }
+}
+
+// Whether the expression resolves, false when it reads a missing field or undeclared variable
+fn resolves(ftx: &FunctionContext, expr: &Expression) -> Result<Value, ExecutionError> {
+    match ftx.ptx.resolve(expr) {
+        Ok(_) => Ok(Value::Bool(true)),
+        Err(err) => missing(err),
+    }
+}
+
+// False for the errors of reading a missing field or undeclared variable, the error otherwise
+fn missing(err: ExecutionError) -> Result<Value, ExecutionError> {
+    match err {
+        ExecutionError::NoSuchKey(_) | ExecutionError::UndeclaredReference(_) => {
+            Ok(Value::Bool(false))
+        }
+        err => Err(err),
+    }
+}
+
+/**
+* Maps a subject to the result paired with the first candidate equal to it, or to the default
+* when none is. The subject is resolved once, and only the candidates up to the match and the
+* matched result are resolved, so results can be host calls.
+*
+* ```cel
+* match(device.tier(), "gold", 3, "silver", 2, 1)
+* ```
+*/
+pub fn match_value(ftx: &FunctionContext) -> Result<Value, ExecutionError> {
+    let [subject, cases @ .., default] = ftx.args.as_slice() else {
+        return Err(ftx.error("match takes a subject, candidate and result pairs and a default"));
+    };
+    if cases.len() % 2 != 0 {
+        return Err(ftx.error(format!(
+            "match takes a subject, candidate and result pairs and a default, got {} arguments",
+            ftx.args.len()
+        )));
+    }
+    let subject = ftx.ptx.resolve(subject)?;
+    for case in cases.chunks(2) {
+        if ftx.ptx.resolve(&case[0])? == subject {
+            return ftx.ptx.resolve(&case[1]);
+        }
+    }
+    ftx.ptx.resolve(default)
+}
+
+/**
+* The elements of a list, or the keys of a map, for which the expression is true with the element
+* bound to the identifier, as a list. Keys of a map are kept in sorted order.
+*
+* ```cel
+* user.purchases.filter(p, p.amount > 100)
+* ```
+*/
+pub fn filter(
+    ftx: &FunctionContext,
+    This(this): This<Value>,
+    ident: Identifier,
+    expr: Expression,
+) -> Result<Value, ExecutionError> {
+    let items: Vec<Value> = match &this {
+        Value::List(items) => items.to_vec(),
+        Value::Map(map) => {
+            let mut keys: Vec<&Key> = map.map.keys().collect();
+            keys.sort();
+            keys.into_iter().map(Value::from).collect()
+        }
+        _ => return Err(this.error_expected_type(ValueType::List)),
+    };
+    let mut ptx = ftx.ptx.new_inner_scope();
+    let mut values = Vec::with_capacity(items.len());
+    for item in items {
+        ptx.add_variable_from_value(ident.0.as_str(), item.clone());
+        if let Value::Bool(true) = ptx.resolve(&expr)? {
+            values.push(item);
+        }
+    }
+    Ok(Value::List(Arc::new(values)))
+}
+
+/**
+* Returns the number of characters of a string, elements of a list, entries of a map or bytes
+* of bytes, as a uint. Strings are counted in characters rather than UTF-8 bytes.
+*
+* ```cel
+* user.tags.size() > 0 && size("héllo") == 5
+* ```
+*/
+pub fn size(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let size = match &this {
+        Value::String(s) => s.chars().count(),
+        Value::List(list) => list.len(),
+        Value::Map(map) => map.map.len(),
+        Value::Bytes(bytes) => bytes.len(),
+        _ => {
+            return Err(ftx.error(format!(
+                "size requires a string, list, map or bytes, got {:?}",
+                this
+            )))
+        }
+    };
+    Ok(Value::UInt(size as u64))
+}
+
+/**
+* Adds two values like `+`, failing instead of concatenating strings whose result would be
+* longer than `max_length` characters. Expressions are rewritten to call it in place of `+` when
+* the `max_string_length` option is set.
+*
+* ```cel
+* "a" + "b" // fails with a limit of 1
+* ```
+*/
+pub fn bounded_add(
+    left: Value,
+    right: Value,
+    max_length: usize,
+    locale: Option<&str>,
+) -> Result<Value, ExecutionError> {
+    if let (Value::String(l), Value::String(r)) = (&left, &right) {
+        let length = l.chars().count() + r.chars().count();
+        if length > max_length {
+            return Err(string_too_long("+", length, max_length, locale));
+        }
+    }
+    left + right
+}
+
+/// Length in characters of the longest string `join` and `repeat` build when the
+/// `max_string_length` option isn't set, so an expression can't exhaust the host's memory
+pub(crate) const MAX_BUILT_STRING_LENGTH: usize = 16 * 1024 * 1024;
+
+/**
+* Joins the elements of a list into a string, each converted like `string()` and separated by
+* the separator. Fails as soon as the result grows longer than `max_length` characters, or
+* [`MAX_BUILT_STRING_LENGTH`] without one.
+*
+* ```cel
+* join(device.categories(), ", ")
+* ```
+*/
+pub fn join(
+    ftx: &FunctionContext,
+    list: Value,
+    separator: &str,
+    max_length: Option<usize>,
+    locale: Option<&str>,
+) -> Result<Value, ExecutionError> {
+    let Value::List(items) = list else {
+        return Err(ftx.error(format!("join requires a list, got {:?}", list)));
+    };
+    let max_length = max_length.unwrap_or(MAX_BUILT_STRING_LENGTH);
+    let separator_length = separator.chars().count();
+    let mut joined = String::new();
+    let mut length = 0;
+    for (index, item) in items.iter().enumerate() {
+        let item = to_string(This(item.clone()));
+        if index > 0 {
+            joined.push_str(separator);
+            length += separator_length;
+        }
+        joined.push_str(&item);
+        length += item.chars().count();
+        if length > max_length {
+            return Err(string_too_long("join", length, max_length, locale));
+        }
+    }
+    Ok(Value::String(Arc::new(joined)))
+}
+
+/**
+* Repeats a string `count` times, giving an empty string for counts of zero or less. Fails
+* without building the string when it would be longer than `max_length` characters, or
+* [`MAX_BUILT_STRING_LENGTH`] without one.
+*
+* ```cel
+* repeat("*", 3) == "***"
+* ```
+*/
+pub fn repeat(
+    This(this): This<Arc<String>>,
+    count: i64,
+    max_length: Option<usize>,
+    locale: Option<&str>,
+) -> Result<Value, ExecutionError> {
+    let count = usize::try_from(count).unwrap_or(0);
+    let max_length = max_length.unwrap_or(MAX_BUILT_STRING_LENGTH);
+    let length = this.chars().count().saturating_mul(count);
+    if length > max_length {
+        return Err(string_too_long("repeat", length, max_length, locale));
+    }
+    Ok(Value::String(Arc::new(this.repeat(count))))
+}
+
+/**
+* The error of the function producing a string of `length` characters, more than the
+* `max_string_length` option allows.
+*/
+pub(crate) fn string_too_long(
+    function: &str,
+    length: usize,
+    max_length: usize,
+    locale: Option<&str>,
+) -> ExecutionError {
+    ExecutionError::FunctionError {
+        function: function.to_string(),
+        message: ErrorMessage::StringTooLong.format(locale, &[&length, &max_length]),
+    }
+}
+
+/**
+* Returns true if the string starts with any of the prefixes in the given list.
+* Non-string elements of the list are skipped.
+*
+* ```cel
+* startsWithAny(device.locale, ["en", "fr", "de"])
+* ```
+*/
+pub fn starts_with_any(
+    ftx: &FunctionContext,
+    This(this): This<Arc<String>>,
+    prefixes: Value,
+) -> Result<bool, ExecutionError> {
+    matches_any(ftx, prefixes, |prefix| this.starts_with(prefix))
+}
+
+/**
+* Returns true if the string ends with any of the suffixes in the given list.
+* Non-string elements of the list are skipped.
+*
+* ```cel
+* endsWithAny(user.email, ["@superwall.com", "@superwall.me"])
+* ```
+*/
+pub fn ends_with_any(
+    ftx: &FunctionContext,
+    This(this): This<Arc<String>>,
+    suffixes: Value,
+) -> Result<bool, ExecutionError> {
+    matches_any(ftx, suffixes, |suffix| this.ends_with(suffix))
+}
+
+/**
+* Escapes the regex metacharacters of the string, so it matches literally when used as
+* (part of) a pattern.
+*
+* ```cel
+* name.matches("^" + escapeRegex(device.prefix))
+* ```
+*/
+pub fn escape_regex(This(this): This<Arc<String>>) -> Arc<String> {
+    Arc::new(regex::escape(&this))
+}
+
+/**
+* Whether the pattern matches the whole string. Unlike `matches`, which is true when the
+* pattern matches anywhere in the string, the pattern is anchored at both ends, so
+* `"abcd".matches("abc")` is true but `"abcd".fullMatch("abc")` is false.
+*
+* ```cel
+* device.appVersion.fullMatch("[0-9]+\\.[0-9]+\\.[0-9]+")
+* ```
+*/
+pub fn full_match(
+    ftx: &FunctionContext,
+    This(this): This<Arc<String>>,
+    pattern: Arc<String>,
+) -> Result<bool, ExecutionError> {
+    match regex::Regex::new(&format!("^(?:{})$", pattern)) {
+        Ok(re) => Ok(re.is_match(&this)),
+        Err(err) => Err(ftx.error(format!("'{pattern}' not a valid regex:\n{err}"))),
+    }
+}
+
+/**
+* Escapes the string for embedding inside a JSON string literal. The surrounding quotes
+* are not added.
+*
+* ```cel
+* "{\"name\": \"" + escapeJson(user.name) + "\"}"
+* ```
+*/
+pub fn escape_json(This(this): This<Arc<String>>) -> Arc<String> {
+    let quoted = serde_json::to_string(this.as_str()).unwrap_or_default();
+    Arc::new(quoted[1..quoted.len() - 1].to_string())
+}
+
+/**
+* Formats a number with a fixed number of decimal digits, like JS's `toFixed`: the exact value of
+* the number is rounded, half away from zero. Negative digits are treated as 0, and non-numeric
+* or non-finite values yield null.
+*
+* ```cel
+* computed.price().toFixed(2) // "4.99"
+* ```
+*/
+pub fn to_fixed(This(this): This<Value>, digits: i64) -> Value {
+    format_number(&this, digits, 1.0, "")
+}
+
+/**
+* Formats a ratio as a percentage with a fixed number of decimal digits, so 0.42 becomes
+* "42.0%" with one digit. Rounding and invalid input are handled like `toFixed`.
+*
+* ```cel
+* computed.rate().toPercent(1) // "42.0%"
+* ```
+*/
+pub fn to_percent(This(this): This<Value>, digits: i64) -> Value {
+    format_number(&this, digits, 100.0, "%")
+}
+
+/**
+* Returns true if the key falls within the first `percent` of 100 buckets. The key is
+* hashed with FNV-1a, which is stable across platforms and releases, so a user stays in
+* or out of a rollout as long as the percentage doesn't shrink. Null or non-scalar keys
+* are never in a rollout.
+*
+* ```cel
+* inRollout(device.userId(), 10)
+* ```
+*/
+pub fn in_rollout(
+    ftx: &FunctionContext,
+    This(key): This<Value>,
+    percent: Value,
+) -> Result<bool, ExecutionError> {
+    let percent = match percent {
+        Value::Int(i) => i as f64,
+        Value::UInt(u) => u as f64,
+        Value::Float(f) => f,
+        _ => return Err(ftx.error("inRollout requires a numeric percentage")),
+    };
+    let key = match key {
+        Value::String(s) => s.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::UInt(u) => u.to_string(),
+        _ => return Ok(false),
+    };
+    Ok(((fnv1a(key.as_bytes()) % 100) as f64) < percent)
+}
+
+/**
+* Divides like `/`, but promotes two integer operands to floats so the result keeps its
+* fraction. Expressions are rewritten to call it when the `integer_division` option is `float`.
+*
+* ```cel
+* 5 / 2 // 2.5
+* ```
+*/
+pub fn float_divide(left: Value, right: Value) -> Result<Value, ExecutionError> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => Ok(Value::Float(l as f64 / r as f64)),
+        (Value::UInt(l), Value::UInt(r)) => Ok(Value::Float(l as f64 / r as f64)),
+        (left, right) => left / right,
+    }
+}
+
+/**
+* Returns the list without duplicates, keeping the first occurrence of each element.
+* Elements are compared with `==`, so numbers of different types are duplicates when
+* they are equal (`[1, 1.0, uint(1)]` becomes `[1]`). A null list stays null.
+*
+* ```cel
+* size(distinct(device.categories()))
+* ```
+*/
+pub fn distinct(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let list = match this {
+        Value::List(list) => list,
+        Value::Null => return Ok(Value::Null),
+        _ => return Err(ftx.error(format!("{} requires a list argument", ftx.name))),
+    };
+    let mut unique: Vec<Value> = Vec::with_capacity(list.len());
+    for item in list.iter() {
+        if !unique.contains(item) {
+            unique.push(item.clone());
+        }
+    }
+    Ok(Value::List(Arc::new(unique)))
+}
+
+/**
+* Flattens one level of nested lists, so `[[1, 2], 3, [[4]]]` becomes `[1, 2, 3, [4]]`.
+* Non-list elements are kept as they are. A null list stays null.
+*
+* ```cel
+* "vip" in flatten(device.nestedTags())
+* ```
+*/
+pub fn flatten(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let list = match this {
+        Value::List(list) => list,
+        Value::Null => return Ok(Value::Null),
+        _ => return Err(ftx.error(format!("{} requires a list argument", ftx.name))),
+    };
+    let mut flat = Vec::with_capacity(list.len());
+    for item in list.iter() {
+        match item {
+            Value::List(nested) => flat.extend(nested.iter().cloned()),
+            _ => flat.push(item.clone()),
+        }
+    }
+    Ok(Value::List(Arc::new(flat)))
+}
+
+/**
+* Returns the map without its null-valued entries, so optional host values can be left out of
+* a result map instead of being sent as null. Only top-level entries are removed. A null map
+* stays null.
+*
+* ```cel
+* omitNull({"price": device.price(), "currency": "USD"})
+* ```
+*/
+pub fn omit_null(ftx: &FunctionContext, This(this): This<Value>) -> Result<Value, ExecutionError> {
+    let map = match this {
+        Value::Map(map) => map,
+        Value::Null => return Ok(Value::Null),
+        _ => return Err(ftx.error(format!("{} requires a map argument", ftx.name))),
+    };
+    let entries = map
+        .map
+        .iter()
+        .filter(|(_, value)| !matches!(value, Value::Null))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    Ok(Value::Map(Map {
+        map: Arc::new(entries),
+    }))
+}
+
+// Seconds since the Unix epoch of a timestamp argument, given as a CEL timestamp, as seconds
+// (how `PassableValue::Timestamp` values reach CEL) or as an RFC 3339 string
+fn epoch_seconds(value: &Value) -> Option<i64> {
+    match value {
+        Value::Timestamp(timestamp) => Some(timestamp.timestamp()),
+        Value::Int(seconds) => Some(*seconds),
+        Value::UInt(seconds) => i64::try_from(*seconds).ok(),
+        Value::String(timestamp) => DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .map(|timestamp| timestamp.timestamp()),
+        _ => None,
+    }
+}
+
+/**
+* The smallest of the arguments, or of the elements of a single list argument. Null values are
+* skipped, so `min(device.a(), 5)` is 5 when the host has no `a`, and the result is null only
+* when no value is left. Numbers of different types compare by value and keep their type:
+* `min(2, 1.5)` is `1.5`.
+*
+* ```cel
+* min(device.daysSinceInstall(), device.daysSinceLogin())
+* ```
+*/
+pub fn min(Arguments(args): Arguments) -> Result<Value, ExecutionError> {
+    extreme("min", &args, Ordering::Less)
+}
+
+/**
+* The largest of the arguments, or of the elements of a single list argument, skipping null
+* values like `min`.
+*
+* ```cel
+* max(device.a(), device.b(), 0)
+* ```
+*/
+pub fn max(Arguments(args): Arguments) -> Result<Value, ExecutionError> {
+    extreme("max", &args, Ordering::Greater)
+}
+
+// The first non-null value ordered `wanted` relative to all the others, or null if there is none
+fn extreme(function: &str, args: &[Value], wanted: Ordering) -> Result<Value, ExecutionError> {
+    let values = match args {
+        [Value::List(values)] => values.as_slice(),
+        args => args,
+    };
+    let mut extreme: Option<&Value> = None;
+    for value in values.iter().filter(|value| !matches!(value, Value::Null)) {
+        extreme = match extreme {
+            None => Some(value),
+            Some(current) => match value.partial_cmp(current) {
+                Some(ordering) if ordering == wanted => Some(value),
+                Some(_) => Some(current),
+                None => {
+                    return Err(ExecutionError::function_error(
+                        function,
+                        format!("cannot compare {:?} with {:?}", current, value),
+                    ))
+                }
+            },
+        };
+    }
+    Ok(extreme.cloned().unwrap_or(Value::Null))
+}
+
+/**
+* Orders two strings by the collation: bytewise, or ignoring case and the accents of Latin
+* letters with ties broken bytewise, so the order stays total and deterministic.
+*/
+pub fn collate(left: &str, right: &str, collation: Collation) -> Ordering {
+    match collation {
+        Collation::Binary => left.cmp(right),
+        Collation::CaseInsensitive => fold_case_and_accents(left)
+            .cmp(fold_case_and_accents(right))
+            .then_with(|| left.cmp(right)),
+    }
+}
+
+// The lowercase letters of the string with the accents of Latin letters removed
+fn fold_case_and_accents(s: &str) -> impl Iterator<Item = char> + '_ {
+    s.chars().flat_map(char::to_lowercase).map(|c| match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => 'a',
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => 'c',
+        'ď' | 'đ' => 'd',
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => 'e',
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => 'g',
+        'ĥ' | 'ħ' => 'h',
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => 'i',
+        'ĵ' => 'j',
+        'ķ' => 'k',
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => 'l',
+        'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => 'o',
+        'ŕ' | 'ŗ' | 'ř' => 'r',
+        'ś' | 'ŝ' | 'ş' | 'š' => 's',
+        'ţ' | 'ť' | 'ŧ' => 't',
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => 'u',
+        'ŵ' => 'w',
+        'ý' | 'ÿ' | 'ŷ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        c => c,
+    })
+}
+
+/**
+* Evaluates the ordering `op` (`<`, `<=`, `>` or `>=`) like CEL's relations, except that strings
+* are ordered by the collation. Expressions are rewritten to call it in place of those relations.
+* Values of types that can't be ordered against each other, like a list and a string, compare
+* false wherever the relation is nested, the same as `==` between mismatched types. Orderings with
+* null still fail, to evaluate to null, and so do those of two bytes values.
+*
+* ```cel
+* "apple" < "Banana" // true with the case_insensitive collation
+* !(["a"] > "premium") // true
+* ```
+*/
+pub fn ordered_compare(
+    left: Value,
+    right: Value,
+    op: Arc<String>,
+    collation: Collation,
+) -> Result<Value, ExecutionError> {
+    let ordering = match (&left, &right) {
+        (Value::String(l), Value::String(r)) => Some(collate(l, r, collation)),
+        (left, right) => left.partial_cmp(right),
+    };
+    let Some(ordering) = ordering else {
+        return match (&left, &right) {
+            (Value::Null, _) | (_, Value::Null) | (Value::Bytes(_), Value::Bytes(_)) => {
+                Err(ExecutionError::ValuesNotComparable(left, right))
+            }
+            _ => Ok(Value::Bool(false)),
+        };
+    };
+    Ok(Value::Bool(match op.as_str() {
+        "<" => ordering.is_lt(),
+        "<=" => ordering.is_le(),
+        ">" => ordering.is_gt(),
+        _ => ordering.is_ge(),
+    }))
+}
+
+/**
+* Returns the list sorted in ascending order, strings ordered by the `collation` option. Numbers of
+* different types are ordered by value, and null elements come last. A null list stays null, and
+* a list of elements that can't be ordered with each other, like strings and numbers or NaN, fails.
+*
+* ```cel
+* device.languages().sort()[0]
+* ```
+*/
+pub fn sort(
+    ftx: &FunctionContext,
+    this: Value,
+    collation: Collation,
+) -> Result<Value, ExecutionError> {
+    let list = match this {
+        Value::List(list) => list,
+        Value::Null => return Ok(Value::Null),
+        _ => return Err(ftx.error(format!("{} requires a list argument", ftx.name))),
+    };
+    // Values compare with the values of their own kind, so checking every element against the
+    // first one, and itself for NaN, checks that they can all be ordered with each other
+    let mut elements = list.iter().filter(|value| !matches!(value, Value::Null));
+    if let Some(first) = elements.next() {
+        for value in std::iter::once(first).chain(elements) {
+            if compare_sortable(first, value).is_none() || compare_sortable(value, value).is_none()
+            {
+                return Err(ftx.error(format!("cannot compare {:?} with {:?}", first, value)));
+            }
+        }
+    }
+    let mut sorted = list.as_ref().clone();
+    sorted.sort_by(|left, right| match (left, right) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Greater,
+        (_, Value::Null) => Ordering::Less,
+        (Value::String(l), Value::String(r)) => collate(l, r, collation),
+        (left, right) => compare_sortable(left, right).unwrap_or(Ordering::Equal),
+    });
+    Ok(Value::List(Arc::new(sorted)))
+}
+
+// The total order of two values of the same kind for `sort`, or `None` when they can't be ordered.
+// Numbers are compared exactly, as ints too close to tell apart as floats would otherwise both
+// equal the same float while differing from each other.
+fn compare_sortable(left: &Value, right: &Value) -> Option<Ordering> {
+    let integer = |value: &Value| match value {
+        Value::Int(i) => Some(*i as i128),
+        Value::UInt(u) => Some(*u as i128),
+        _ => None,
+    };
+    let float = |value: &Value| match value {
+        Value::Float(f) if !f.is_nan() => Some(*f),
+        Value::Int(i) => Some(*i as f64),
+        Value::UInt(u) => Some(*u as f64),
+        _ => None,
+    };
+    match (left, right) {
+        (Value::Int(_) | Value::UInt(_), Value::Int(_) | Value::UInt(_)) => {
+            Some(integer(left)?.cmp(&integer(right)?))
+        }
+        (Value::Int(_) | Value::UInt(_) | Value::Float(_), _) => {
+            let (l, r) = (float(left)?, float(right)?);
+            // A float equal to an int is integral and within the range of ints, so exact as one
+            Some(l.partial_cmp(&r)?.then_with(|| {
+                let exact = |value: &Value, f: f64| integer(value).unwrap_or(f as i128);
+                exact(left, l).cmp(&exact(right, r))
+            }))
+        }
+        (Value::String(_), Value::String(_))
+        | (Value::Bool(_), Value::Bool(_))
+        | (Value::Duration(_), Value::Duration(_))
+        | (Value::Timestamp(_), Value::Timestamp(_)) => left.partial_cmp(right),
+        _ => None,
+    }
+}
+
+/**
+* Parses an RFC 3339 timestamp normalized to UTC, so the same instant written with different
+* offsets is the same timestamp, compared and printed alike. Seconds since the Unix epoch, how
+* `PassableValue::Timestamp` values reach CEL, and timestamps are accepted too, so host
+* timestamps can be compared with parsed ones.
+*
+* ```cel
+* timestamp("2023-12-31T19:00:00-05:00") == timestamp("2024-01-01T00:00:00Z")
+* ```
+*/
+pub fn timestamp(ftx: &FunctionContext, value: Value) -> Result<Value, ExecutionError> {
+    let timestamp = match &value {
+        Value::Timestamp(timestamp) => *timestamp,
+        Value::String(timestamp) => {
+            DateTime::parse_from_rfc3339(timestamp).map_err(|e| ftx.error(e.to_string()))?
+        }
+        Value::Int(_) | Value::UInt(_) => epoch_seconds(&value)
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .ok_or_else(|| ftx.error("timestamp out of range"))?
+            .fixed_offset(),
+        _ => {
+            return Err(ftx.error(format!(
+                "timestamp requires a string or seconds since the Unix epoch, got {:?}",
+                value
+            )))
+        }
+    };
+    Ok(Value::Timestamp(
+        timestamp.with_timezone(&FixedOffset::east_opt(0).unwrap()),
+    ))
+}
+
+/**
+* Converts a string to its UTF-8 bytes, so host bytes like signatures and checksums can be
+* compared with a literal. Bytes are returned as they are.
+*
+* ```cel
+* device.signature() == bytes("a1b2")
+* ```
+*/
+pub fn bytes(ftx: &FunctionContext, value: Value) -> Result<Value, ExecutionError> {
+    match value {
+        Value::String(s) => Ok(Value::Bytes(Arc::new(s.as_bytes().to_vec()))),
+        Value::Bytes(bytes) => Ok(Value::Bytes(bytes)),
+        value => Err(ftx.error(format!("bytes requires a string, got {:?}", value))),
+    }
+}
+
+/**
+* Whether the timestamp falls within the window from `start`, inclusive, to `end`, exclusive.
+* Instants are compared, so timestamps with different UTC offsets, e.g. on either side of a DST
+* change, compare correctly. A null or invalid timestamp or bound gives null.
+*
+* ```cel
+* withinWindow(user.lastSeen, timestamp("2024-03-01T00:00:00Z"), timestamp("2024-04-01T00:00:00Z"))
+* ```
+*/
+pub fn within_window(This(timestamp): This<Value>, start: Value, end: Value) -> Value {
+    match (
+        epoch_seconds(&timestamp),
+        epoch_seconds(&start),
+        epoch_seconds(&end),
+    ) {
+        (Some(timestamp), Some(start), Some(end)) => {
+            Value::Bool(start <= timestamp && timestamp < end)
+        }
+        _ => Value::Null,
+    }
+}
+
+/**
+* The number of whole days elapsed between the timestamp and `now`, both in seconds since the
+* Unix epoch. Days are 24-hour periods, so a DST change doesn't shift the count, and future
+* timestamps give negative ages. A null or invalid timestamp gives null.
+*
+* ```cel
+* ageInDays(user.lastSeen) <= 7 && ageInDays(user.lastSeen) >= 1
+* ```
+*/
+pub fn age_in_days(timestamp: Value, now: i64) -> Value {
+    epoch_seconds(&timestamp)
+        .and_then(|timestamp| now.checked_sub(timestamp))
+        .map_or(Value::Null, |age| Value::Int(age.div_euclid(86_400)))
+}
+
+/// 64-bit FNV-1a, a small hash that is stable across platforms and releases
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// f64 has at most 17 significant decimal digits, more would only print noise
+const MAX_FRACTION_DIGITS: i64 = 17;
+
+// Every f64 has a finite decimal expansion, of at most 1074 fraction digits
+const EXACT_FRACTION_DIGITS: usize = 1074;
+
+fn format_number(value: &Value, digits: i64, scale: f64, suffix: &str) -> Value {
+    let number = match value {
+        Value::Int(i) => *i as f64,
+        Value::UInt(u) => *u as f64,
+        Value::Float(f) => *f,
+        _ => return Value::Null,
+    } * scale;
+    if !number.is_finite() {
+        return Value::Null;
+    }
+    let digits = digits.clamp(0, MAX_FRACTION_DIGITS) as usize;
+    Value::String(Arc::new(format!("{}{}", fixed(number, digits), suffix)))
+}
+
+// The number with `digits` fraction digits. Formatting rounds the exact value, but ties to even,
+// so exact ties, like 2.5, are rounded away from zero from the exact digits instead
+fn fixed(number: f64, digits: usize) -> String {
+    let exact = format!("{:.*}", EXACT_FRACTION_DIGITS, number.abs());
+    let point = exact.find('.').unwrap_or(exact.len());
+    let rest = &exact[point + 1 + digits..];
+    if !(rest.starts_with('5') && rest[1..].bytes().all(|b| b == b'0')) {
+        return format!("{:.*}", digits, number);
+    }
+    let kept = if digits == 0 {
+        &exact[..point]
+    } else {
+        &exact[..point + 1 + digits]
+    };
+    // Adds one unit of the last kept digit, carrying through the nines
+    let mut rounded = kept.as_bytes().to_vec();
+    let mut carry = true;
+    for byte in rounded.iter_mut().rev().filter(|byte| **byte != b'.') {
+        if *byte == b'9' {
+            *byte = b'0';
+        } else {
+            *byte += 1;
+            carry = false;
+            break;
+        }
+    }
+    let mut rounded = String::from_utf8(rounded).unwrap_or_default();
+    if carry {
+        rounded.insert(0, '1');
+    }
+    if number < 0.0 {
+        rounded.insert(0, '-');
+    }
+    rounded
+}
+
+fn matches_any(
+    ftx: &FunctionContext,
+    candidates: Value,
+    predicate: impl Fn(&str) -> bool,
+) -> Result<bool, ExecutionError> {
+    match candidates {
+        Value::List(list) => Ok(list.iter().any(|candidate| match candidate {
+            Value::String(candidate) => predicate(candidate.as_str()),
+            _ => false,
+        })),
+        _ => Err(ftx.error(format!("{} requires a list argument", ftx.name))),
+    }
+}
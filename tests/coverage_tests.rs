@@ -133,7 +133,7 @@ fn test_large_uncovered_string_conversion_blocks() {
     // Test parsing numeric strings that should trigger different conversion paths
     let expressions = vec![
         "'42'",                    // Should parse as int
-        "'42.0'",                  // Should parse as float that converts to int
+        "'42.0'",                  // Should parse as float and stay a float
         "'3.14159'",               // Should parse as float
         "'999999999999999999999'", // Large number
         "'true'",                  // Boolean string
@@ -262,7 +262,7 @@ fn test_string_numeric_parsing_comprehensive() {
         ("zero_float", "0.0", "float"),
         ("large_int", "999999999", "int"),
         ("scientific", "1e5", "float"),
-        ("fractional_zero", "42.0", "int"), // Should convert to int
+        ("fractional_zero", "42.0", "float"), // Explicit decimal point stays a float
         ("invalid_number", "not_a_number", "string"),
         ("empty_string", "", "string"),
     ];
@@ -280,11 +280,16 @@ fn test_string_numeric_parsing_comprehensive() {
             }),
         );
 
-        // Should process without error and potentially convert types
-        assert!(!result.is_empty());
-        // For valid numbers, might see type conversion in the result
-        if expected_type == "int" && value != "not_a_number" && value != "" {
-            // Might see int conversion
+        // Variables are not coerced, so the string comes back as it was declared
+        assert_eq!(
+            result,
+            format!(r#"{{"Ok":{{"type":"string","value":"{}"}}}}"#, value)
+        );
+
+        // String literals compared against numbers are coerced to the type they were written as
+        match (expected_type, try_parse_string_to_number(value)) {
+            ("int", Some(Atom::Int(_))) | ("float", Some(Atom::Float(_))) | ("string", None) => {}
+            (expected, parsed) => panic!("{:?} should be {}, got {:?}", value, expected, parsed),
         }
     }
 }
@@ -113,7 +113,7 @@ fn test_displayable_value_formatting_comprehensive() {
 
     // Test Bytes formatting (lines 945-947)
     let bytes_val = DisplayableValue(Value::Bytes(Arc::new(vec![1, 2, 3])));
-    assert_eq!(format!("{}", bytes_val), "bytes go here");
+    assert_eq!(format!("{}", bytes_val), "b\"\\u{1}\\u{2}\\u{3}\"");
 
     // Test Bool formatting (line 948)
     let bool_val = DisplayableValue(Value::Bool(true));
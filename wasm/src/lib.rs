@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 pub use cel_eval::HostContext;
+pub use cel_eval::VariableResolver;
 use wasm_bindgen::prelude::wasm_bindgen;
 use wasm_bindgen::JsValue;
 
@@ -36,7 +37,28 @@ extern "C" {
         args: String,
     ) -> Result<JsValue, JsValue>;
 
+    /**
+    Defines the Rust type and method signatures of the JS variable resolver.
+    */
+    #[wasm_bindgen(typescript_type = "WasmVariableResolver")]
+    pub type JsVariableResolver;
+
+    #[wasm_bindgen(method, catch)]
+    fn resolve_variable(this: &JsVariableResolver, name: String) -> Result<JsValue, JsValue>;
+
+}
+
+/**
+ * The TypeScript definition of the JS variable resolver, as `typescript_type` only names it.
+ * `resolve_variable` returns the variable as a serialized `PassableValue`, or `"null"` to leave
+ * it undeclared.
+ */
+#[wasm_bindgen(typescript_custom_section)]
+const WASM_VARIABLE_RESOLVER: &str = r#"
+export interface WasmVariableResolver {
+    resolve_variable(name: string): string;
 }
+"#;
 
 /**
 * Sets up a panic hook to log panics to the console.
@@ -68,34 +90,24 @@ impl HostContext for HostContextAdapter {
      * This method is used to call the computed property method on the JS Host context.
      * It proxies evaluator calls for `platform.something(arg)` to the JS Host context itself.
      */
-    fn computed_property(&self, name: String, args: String) -> String {
+    fn computed_property(&self, name: String, args: String) -> Result<String, String> {
         let context = Arc::clone(&self.context);
-        let promise = context.computed_property(name.clone(), args.clone());
-        let result = promise
-            .expect("Did not receive the proper result from computed")
-            .as_string();
-        result.clone().expect(
-            format!(
-                "Could not deserialize the result from computed property - Is some: {}",
-                result.is_some()
-            )
-            .as_str(),
-        )
+        let result = context
+            .computed_property(name.clone(), args)
+            .map_err(host_error)?;
+        result
+            .as_string()
+            .ok_or_else(|| format!("The host returned a non-string result for {}", name))
     }
 
-    fn device_property(&self, name: String, args: String) -> String {
+    fn device_property(&self, name: String, args: String) -> Result<String, String> {
         let context = Arc::clone(&self.context);
-        let promise = context.device_property(name.clone(), args.clone());
-        let result = promise
-            .expect("Did not receive the proper result from computed")
-            .as_string();
-        result.clone().expect(
-            format!(
-                "Could not deserialize the result from computed property - Is some: {}",
-                result.is_some()
-            )
-            .as_str(),
-        )
+        let result = context
+            .device_property(name.clone(), args)
+            .map_err(host_error)?;
+        result
+            .as_string()
+            .ok_or_else(|| format!("The host returned a non-string result for {}", name))
     }
 }
 
@@ -103,6 +115,43 @@ unsafe impl Send for HostContextAdapter {}
 
 unsafe impl Sync for HostContextAdapter {}
 
+// The message of an error thrown by the JS host, failing the host call
+fn host_error(error: JsValue) -> String {
+    error
+        .as_string()
+        .unwrap_or_else(|| format!("{:?}", error))
+}
+
+/**
+ * Adapter converting the JS variable resolver into a Rust `VariableResolver`.
+ */
+struct VariableResolverAdapter {
+    resolver: Arc<JsVariableResolver>,
+}
+
+impl VariableResolverAdapter {
+    fn new(resolver: JsVariableResolver) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+        }
+    }
+}
+
+impl VariableResolver for VariableResolverAdapter {
+    fn resolve_variable(&self, name: String) -> String {
+        let resolver = Arc::clone(&self.resolver);
+        resolver
+            .resolve_variable(name)
+            .ok()
+            .and_then(|result| result.as_string())
+            .unwrap_or_else(|| "null".to_string())
+    }
+}
+
+unsafe impl Send for VariableResolverAdapter {}
+
+unsafe impl Sync for VariableResolverAdapter {}
+
 #[wasm_bindgen]
 pub async fn evaluate_with_context(
     definition: String,
@@ -121,16 +170,188 @@ pub async fn evaluate_ast_with_context(
     Ok(cel_eval::evaluate_ast_with_context(definition, adapter))
 }
 
+#[wasm_bindgen]
+pub async fn evaluate_with_resolver(
+    definition: String,
+    context: JsHostContext,
+    resolver: JsVariableResolver,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    let resolver = Arc::new(VariableResolverAdapter::new(resolver));
+    Ok(cel_eval::evaluate_with_resolver(
+        definition, adapter, resolver,
+    ))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_with_base_and_overrides(
+    base: String,
+    overrides: String,
+    expression: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_with_base_and_overrides(
+        base, overrides, expression, adapter,
+    ))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_conditions_with_context(
+    definition: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_conditions_with_context(
+        definition, adapter,
+    ))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_with_normalizations(
+    definition: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_with_normalizations(definition, adapter))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_with_warnings(
+    definition: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_with_warnings(definition, adapter))
+}
+
+#[wasm_bindgen]
+pub async fn explain_decision(
+    definition: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::explain_decision(definition, adapter))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_with_provenance(
+    definition: String,
+    context: JsHostContext,
+) -> Result<String, JsValue> {
+    let adapter = Arc::new(HostContextAdapter::new(context));
+    Ok(cel_eval::evaluate_with_provenance(definition, adapter))
+}
+
+/**
+ * A context held for a long-lived session, whose variables are updated one at a time.
+ */
+#[wasm_bindgen]
+pub struct EvaluationSession {
+    session: cel_eval::EvaluationSession,
+}
+
+#[wasm_bindgen]
+impl EvaluationSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(definition: String) -> Result<EvaluationSession, JsValue> {
+        cel_eval::EvaluationSession::new(definition)
+            .map(|session| EvaluationSession { session })
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn update_variable(&self, path: String, value: String) -> Result<(), JsValue> {
+        self.session
+            .update_variable(path, value)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn evaluate(&self, expression: String, context: JsHostContext) -> String {
+        let adapter = Arc::new(HostContextAdapter::new(context));
+        self.session.evaluate(expression, adapter)
+    }
+}
+
 #[wasm_bindgen]
 pub async fn evaluate_ast(ast: String) -> Result<String, JsValue> {
     Ok(cel_eval::evaluate_ast(ast))
 }
 
+#[wasm_bindgen]
+pub async fn evaluate_on_json(expression: String, data: String) -> Result<String, JsValue> {
+    Ok(cel_eval::evaluate_on_json(expression, data))
+}
+
+#[wasm_bindgen]
+pub async fn evaluate_batch_for_golden(cases: String) -> Result<String, JsValue> {
+    Ok(cel_eval::evaluate_batch_for_golden(cases))
+}
+
 #[wasm_bindgen]
 pub async fn parse_into_ast(expression: String) -> Result<String, JsValue> {
     Ok(cel_eval::parse_to_ast(expression))
 }
 
+#[wasm_bindgen]
+pub async fn untransform_null_safety(ast: String) -> Result<String, JsValue> {
+    Ok(cel_eval::untransform_null_safety(ast))
+}
+
+#[wasm_bindgen]
+pub async fn validate_expression(expression: String) -> Result<String, JsValue> {
+    Ok(cel_eval::validate_expression(expression))
+}
+
+#[wasm_bindgen]
+pub async fn expression_diagnostics(expression: String) -> Result<String, JsValue> {
+    Ok(cel_eval::expression_diagnostics(expression))
+}
+
+#[wasm_bindgen]
+pub async fn validate_context(definition: String) -> Result<String, JsValue> {
+    Ok(cel_eval::validate_context(definition))
+}
+
+#[wasm_bindgen]
+pub async fn tokenize_expression(expression: String) -> Result<String, JsValue> {
+    Ok(cel_eval::tokenize_expression(expression))
+}
+
+#[wasm_bindgen]
+pub async fn context_fingerprint(definition: String) -> Result<String, JsValue> {
+    Ok(cel_eval::context_fingerprint(definition))
+}
+
+#[wasm_bindgen]
+pub async fn collect_referenced_variables(expression: String) -> Result<String, JsValue> {
+    Ok(cel_eval::collect_referenced_variables(expression))
+}
+
+#[wasm_bindgen]
+pub async fn collect_unused_declarations(definition: String) -> Result<String, JsValue> {
+    Ok(cel_eval::collect_unused_declarations(definition))
+}
+
+#[wasm_bindgen]
+pub async fn expression_warnings(expression: String) -> Result<String, JsValue> {
+    Ok(cel_eval::expression_warnings(expression))
+}
+
+#[wasm_bindgen]
+pub async fn estimate_cost(expression: String, context: String) -> Result<String, JsValue> {
+    Ok(cel_eval::estimate_cost(expression, context))
+}
+
+#[wasm_bindgen]
+pub async fn dialect_info() -> Result<String, JsValue> {
+    Ok(cel_eval::dialect_info())
+}
+
+#[wasm_bindgen]
+pub async fn error_message(code: String, locale: String) -> Result<String, JsValue> {
+    Ok(cel_eval::error_message(code, locale))
+}
+
 #[cfg(test)]
 mod tests {
     #[test]